@@ -0,0 +1,415 @@
+use crate::game::Game;
+use crate::scanner::{Matches, Scanner, Section};
+use anyhow::{anyhow, bail, Result};
+use std::fmt::{Display, Formatter};
+
+pub struct AddressExpression {
+    text: String,
+    base: Expression,
+    hops: Vec<Expression>,
+}
+
+enum Expression {
+    Number(usize),
+    Module(String),
+    Signature(String),
+    Dereference(Box<Expression>),
+    Add(Box<Expression>, Box<Expression>),
+    Subtract(Box<Expression>, Box<Expression>),
+    Multiply(Box<Expression>, Box<Expression>),
+}
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Number(usize),
+    Name(String),
+    String(String),
+    Plus,
+    Minus,
+    Star,
+    Arrow,
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+}
+
+impl AddressExpression {
+    pub fn parse(text: &str) -> Result<Self> {
+        let tokens = tokenize(text).map_err(|error| invalid(text, error))?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+
+        let base = parser.expression().map_err(|error| invalid(text, error))?;
+        let mut hops = Vec::new();
+        while parser.eat(&Token::Arrow) {
+            let hop = match parser.peek() {
+                None | Some(Token::Arrow) => Expression::Number(0),
+                Some(Token::Plus | Token::Minus) => parser
+                    .sum(Expression::Number(0))
+                    .map_err(|error| invalid(text, error))?,
+                Some(token) => {
+                    let error = format!("expected an offset after \"->\", found {token}");
+                    return Err(invalid(text, error));
+                }
+            };
+            hops.push(hop);
+        }
+
+        if let Some(token) = parser.peek() {
+            return Err(invalid(text, format!("unexpected {token}")));
+        }
+
+        Ok(Self {
+            text: text.trim().into(),
+            base,
+            hops,
+        })
+    }
+
+    pub fn resolve(&self, game: &Game) -> Result<usize> {
+        let mut address = self
+            .base
+            .evaluate(game)
+            .map_err(|error| anyhow!("Couldn't resolve base of \"{self}\" ({error})"))?;
+
+        for (index, hop) in self.hops.iter().enumerate() {
+            let hop_number = index + 1;
+            let pointer: usize = game.process.read_into(address).map_err(|error| {
+                anyhow!("Hop {hop_number} of \"{self}\" couldn't read pointer at {address:#X} ({error})")
+            })?;
+            if pointer == 0 {
+                bail!("Hop {hop_number} of \"{self}\" found a null pointer at {address:#X}");
+            }
+
+            let offset = hop.evaluate(game).map_err(|error| {
+                anyhow!("Couldn't resolve offset of hop {hop_number} of \"{self}\" ({error})")
+            })?;
+            address = pointer.wrapping_add(offset);
+        }
+
+        Ok(address)
+    }
+}
+
+impl Display for AddressExpression {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str(&self.text)
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(value) => write!(formatter, "{value:#X}"),
+            Token::Name(name) => write!(formatter, "\"{name}\""),
+            Token::String(string) => write!(formatter, "string \"{string}\""),
+            Token::Plus => formatter.write_str("\"+\""),
+            Token::Minus => formatter.write_str("\"-\""),
+            Token::Star => formatter.write_str("\"*\""),
+            Token::Arrow => formatter.write_str("\"->\""),
+            Token::OpenParen => formatter.write_str("\"(\""),
+            Token::CloseParen => formatter.write_str("\")\""),
+            Token::OpenBracket => formatter.write_str("\"[\""),
+            Token::CloseBracket => formatter.write_str("\"]\""),
+        }
+    }
+}
+
+impl Expression {
+    fn evaluate(&self, game: &Game) -> Result<usize> {
+        Ok(match self {
+            Expression::Number(value) => *value,
+            Expression::Module(name) => game.module_address(name, 0)?,
+            Expression::Signature(pattern) => {
+                let mut scanner = Scanner::new();
+                let id = scanner.add(Section::Code, Matches::Unique, pattern);
+                scanner.scan(game.process.as_ref()).take(id)?.address
+            }
+            Expression::Dereference(expression) => {
+                let address = expression.evaluate(game)?;
+                game.process
+                    .read_into(address)
+                    .map_err(|error| anyhow!("Couldn't read pointer at {address:#X} ({error})"))?
+            }
+            Expression::Add(left, right) => {
+                left.evaluate(game)?.wrapping_add(right.evaluate(game)?)
+            }
+            Expression::Subtract(left, right) => {
+                left.evaluate(game)?.wrapping_sub(right.evaluate(game)?)
+            }
+            Expression::Multiply(left, right) => {
+                left.evaluate(game)?.wrapping_mul(right.evaluate(game)?)
+            }
+        })
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned()?;
+        self.position += 1;
+        Some(token)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), String> {
+        match self.next() {
+            Some(next) if next == token => Ok(()),
+            Some(next) => Err(format!("expected {token}, found {next}")),
+            None => Err(format!("expected {token}, found end of expression")),
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expression, String> {
+        let left = self.product()?;
+        self.sum(left)
+    }
+
+    fn sum(&mut self, mut left: Expression) -> Result<Expression, String> {
+        loop {
+            if self.eat(&Token::Plus) {
+                left = Expression::Add(Box::new(left), Box::new(self.product()?));
+            } else if self.eat(&Token::Minus) {
+                left = Expression::Subtract(Box::new(left), Box::new(self.product()?));
+            } else {
+                return Ok(left);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression, String> {
+        let mut left = self.primary()?;
+        while self.eat(&Token::Star) {
+            left = Expression::Multiply(Box::new(left), Box::new(self.primary()?));
+        }
+        Ok(left)
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            Some(Token::Name(name)) if name == "sig" => {
+                self.expect(Token::OpenParen)?;
+                let pattern = match self.next() {
+                    Some(Token::String(pattern)) => pattern,
+                    _ => return Err("expected a quoted signature in sig(...)".into()),
+                };
+                self.expect(Token::CloseParen)?;
+                Ok(Expression::Signature(pattern))
+            }
+            Some(Token::Name(name)) => Ok(Expression::Module(name)),
+            Some(Token::OpenParen) => {
+                let expression = self.expression()?;
+                self.expect(Token::CloseParen)?;
+                Ok(expression)
+            }
+            Some(Token::OpenBracket) => {
+                let expression = self.expression()?;
+                self.expect(Token::CloseBracket)?;
+                Ok(Expression::Dereference(Box::new(expression)))
+            }
+            Some(token) => Err(format!("unexpected {token}")),
+            None => Err("unexpected end of expression".into()),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((start, char)) = chars.next() {
+        let token = match char {
+            ' ' | '\t' => continue,
+            '+' => Token::Plus,
+            '*' => Token::Star,
+            '(' => Token::OpenParen,
+            ')' => Token::CloseParen,
+            '[' => Token::OpenBracket,
+            ']' => Token::CloseBracket,
+            '-' => match chars.next_if(|(_, char)| *char == '>') {
+                Some(_) => Token::Arrow,
+                None => Token::Minus,
+            },
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, char)) => string.push(char),
+                        None => return Err(format!("unterminated string at position {start}")),
+                    }
+                }
+                Token::String(string)
+            }
+            _ if char.is_ascii_alphanumeric() || char == '_' => {
+                let mut end = start + char.len_utf8();
+                while let Some((index, char)) = chars.next_if(|(_, char)| {
+                    char.is_ascii_alphanumeric() || *char == '_' || *char == '.'
+                }) {
+                    end = index + char.len_utf8();
+                }
+
+                let word = &text[start..end];
+                if !char.is_ascii_digit() {
+                    Token::Name(word.into())
+                } else if let Some(hex) = word.strip_prefix("0x").or(word.strip_prefix("0X")) {
+                    let value = usize::from_str_radix(hex, 16)
+                        .map_err(|_| format!("invalid hex number \"{word}\""))?;
+                    Token::Number(value)
+                } else {
+                    let value = word
+                        .parse()
+                        .map_err(|_| format!("invalid number \"{word}\""))?;
+                    Token::Number(value)
+                }
+            }
+            _ => return Err(format!("unexpected '{char}' at position {start}")),
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn invalid(text: &str, error: String) -> anyhow::Error {
+    anyhow!("Invalid address expression \"{}\": {error}", text.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BufferBackend;
+    use crate::test_util::{backend, BASE};
+    use std::sync::Arc;
+
+    const MODULE: usize = 0x10000;
+    const HEAP: usize = 0x20000;
+
+    fn game() -> Game {
+        let mut module = vec![0; 0x1000];
+        module[0x100..0x108].copy_from_slice(&HEAP.to_le_bytes());
+        let mut heap = vec![0; 0x1000];
+        heap[0x18..0x20].copy_from_slice(&(HEAP + 0x800).to_le_bytes());
+        let process = BufferBackend::new(MODULE)
+            .with_module("ACMirage.exe", MODULE, module)
+            .with_region(HEAP, heap);
+        Game::new(Arc::new(process))
+    }
+
+    fn resolve(game: &Game, expression: &str) -> Result<usize> {
+        AddressExpression::parse(expression)?.resolve(game)
+    }
+
+    fn error<T>(result: Result<T>) -> String {
+        match result {
+            Ok(_) => panic!("Expected an error"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn evaluates_arithmetic() {
+        let game = game();
+        assert_eq!(resolve(&game, "0x10 + 2 * (3 - 1)").unwrap(), 0x14);
+        assert_eq!(resolve(&game, "16 - 0x4 - 2").unwrap(), 10);
+        assert_eq!(
+            resolve(&game, "acmirage.exe + 0x10").unwrap(),
+            MODULE + 0x10
+        );
+    }
+
+    #[test]
+    fn follows_pointer_chains() {
+        let game = game();
+        assert_eq!(
+            resolve(&game, "ACMirage.exe+0x100 -> +0x18 -> +0x2C").unwrap(),
+            HEAP + 0x82C
+        );
+        assert_eq!(
+            resolve(&game, "ACMirage.exe+0x100 -> -8").unwrap(),
+            HEAP - 8
+        );
+        assert_eq!(resolve(&game, "ACMirage.exe+0x100 ->").unwrap(), HEAP);
+        assert_eq!(
+            resolve(&game, "[ACMirage.exe+0x100] + 8").unwrap(),
+            HEAP + 8
+        );
+    }
+
+    #[test]
+    fn reports_failing_hops() {
+        let game = game();
+        assert_eq!(
+            error(resolve(&game, "ACMirage.exe+0x100 -> +0x20 -> +0x2C")),
+            "Hop 2 of \"ACMirage.exe+0x100 -> +0x20 -> +0x2C\" found a null pointer at 0x20020"
+        );
+        assert!(error(resolve(&game, "0x50000 -> +8"))
+            .starts_with("Hop 1 of \"0x50000 -> +8\" couldn't read pointer at 0x50000"));
+        assert!(error(resolve(&game, "Missing.dll + 4"))
+            .starts_with("Couldn't resolve base of \"Missing.dll + 4\""));
+    }
+
+    #[test]
+    fn resolves_signatures() {
+        let mut code = vec![0xCC; 0x40];
+        code[0x20..0x24].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        let game = Game::new(Arc::new(backend(&[(".text", &code)])));
+        assert_eq!(
+            resolve(&game, "sig(\"DE AD ?? EF\") + 4").unwrap(),
+            BASE + 0x1024
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        let parse = |expression| error(AddressExpression::parse(expression));
+        assert_eq!(
+            parse("ACMirage.exe +"),
+            "Invalid address expression \"ACMirage.exe +\": unexpected end of expression"
+        );
+        assert_eq!(
+            parse("0x10 -> 4"),
+            "Invalid address expression \"0x10 -> 4\": expected an offset after \"->\", found 0x4"
+        );
+        assert_eq!(
+            parse("(1 + 2"),
+            "Invalid address expression \"(1 + 2\": expected \")\", found end of expression"
+        );
+        assert_eq!(
+            parse("sig(\"F3 0F"),
+            "Invalid address expression \"sig(\"F3 0F\": unterminated string at position 4"
+        );
+        assert_eq!(
+            parse("0xZZ"),
+            "Invalid address expression \"0xZZ\": invalid hex number \"0xZZ\""
+        );
+        assert_eq!(
+            parse("1 $ 2"),
+            "Invalid address expression \"1 $ 2\": unexpected '$' at position 2"
+        );
+        assert_eq!(
+            parse("1 2"),
+            "Invalid address expression \"1 2\": unexpected 0x2"
+        );
+    }
+}
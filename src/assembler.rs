@@ -0,0 +1,458 @@
+use crate::x86;
+use anyhow::{anyhow, bail, Result};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Register {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Xmm {
+    Xmm0,
+    Xmm1,
+    Xmm2,
+    Xmm3,
+    Xmm4,
+    Xmm5,
+    Xmm6,
+    Xmm7,
+    Xmm8,
+    Xmm9,
+    Xmm10,
+    Xmm11,
+    Xmm12,
+    Xmm13,
+    Xmm14,
+    Xmm15,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Label(usize);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Target {
+    Address(usize),
+    Label(Label),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Memory {
+    // rip-relative
+    Rip(Target),
+    Base(Register, i32),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Operand {
+    Register(Register),
+    Xmm(Xmm),
+    Memory(Memory),
+}
+
+pub struct Assembler {
+    address: usize,
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, Target)>,
+    error: Option<anyhow::Error>,
+}
+
+impl Assembler {
+    pub fn new(address: usize) -> Self {
+        Self {
+            address,
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        if self.labels[label.0].is_some() {
+            self.fail(anyhow!("Label {} is bound twice", label.0));
+        }
+        self.labels[label.0] = Some(self.code.len());
+        self
+    }
+
+    pub fn mov(
+        &mut self,
+        destination: impl Into<Operand>,
+        source: impl Into<Operand>,
+    ) -> &mut Self {
+        match (destination.into(), source.into()) {
+            (
+                Operand::Register(destination),
+                source @ (Operand::Register(_) | Operand::Memory(_)),
+            ) => self.instruction(None, true, &[0x8B], destination as u8, source),
+            (destination @ Operand::Memory(_), Operand::Register(source)) => {
+                self.instruction(None, true, &[0x89], source as u8, destination)
+            }
+            (destination, source) => self.invalid("mov", destination, source),
+        }
+    }
+
+    pub fn mov_immediate(&mut self, destination: Register, value: u64) -> &mut Self {
+        let register = destination as u8;
+        self.code.push(0x48 | (register >> 3));
+        self.code.push(0xB8 + (register & 0x07));
+        self.code.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn movss(
+        &mut self,
+        destination: impl Into<Operand>,
+        source: impl Into<Operand>,
+    ) -> &mut Self {
+        match (destination.into(), source.into()) {
+            (Operand::Xmm(destination), source @ (Operand::Xmm(_) | Operand::Memory(_))) => {
+                self.instruction(Some(0xF3), false, &[0x0F, 0x10], destination as u8, source)
+            }
+            (destination @ Operand::Memory(_), Operand::Xmm(source)) => {
+                self.instruction(Some(0xF3), false, &[0x0F, 0x11], source as u8, destination)
+            }
+            (destination, source) => self.invalid("movss", destination, source),
+        }
+    }
+
+    pub fn addss(&mut self, destination: Xmm, source: impl Into<Operand>) -> &mut Self {
+        self.scalar("addss", 0x58, destination, source.into())
+    }
+
+    pub fn mulss(&mut self, destination: Xmm, source: impl Into<Operand>) -> &mut Self {
+        self.scalar("mulss", 0x59, destination, source.into())
+    }
+
+    pub fn jmp(&mut self, target: impl Into<Target>) -> &mut Self {
+        self.code.push(0xE9);
+        self.relative(target.into())
+    }
+
+    pub fn call(&mut self, target: impl Into<Target>) -> &mut Self {
+        self.code.push(0xE8);
+        self.relative(target.into())
+    }
+
+    pub fn nop(&mut self) -> &mut Self {
+        self.code.push(0x90);
+        self
+    }
+
+    pub fn f32(&mut self, value: f32) -> &mut Self {
+        self.code.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        for (position, target) in self.fixups {
+            let target = match target {
+                Target::Address(address) => address,
+                Target::Label(label) => match self.labels[label.0] {
+                    Some(offset) => self.address + offset,
+                    None => bail!("Label {} is never bound", label.0),
+                },
+            };
+            let displacement = x86::rel32(self.address + position + 4, target)?;
+            self.code[position..(position + 4)].copy_from_slice(&displacement);
+        }
+
+        Ok(self.code)
+    }
+
+    fn scalar(&mut self, name: &str, opcode: u8, destination: Xmm, source: Operand) -> &mut Self {
+        match source {
+            Operand::Xmm(_) | Operand::Memory(_) => self.instruction(
+                Some(0xF3),
+                false,
+                &[0x0F, opcode],
+                destination as u8,
+                source,
+            ),
+            source => self.invalid(name, Operand::Xmm(destination), source),
+        }
+    }
+
+    fn instruction(
+        &mut self,
+        prefix: Option<u8>,
+        wide: bool,
+        opcode: &[u8],
+        reg: u8,
+        rm: Operand,
+    ) -> &mut Self {
+        let (rm_code, memory) = match rm {
+            Operand::Register(register) => (register as u8, None),
+            Operand::Xmm(xmm) => (xmm as u8, None),
+            Operand::Memory(Memory::Rip(target)) => (0, Some(Memory::Rip(target))),
+            Operand::Memory(Memory::Base(base, displacement)) => {
+                (base as u8, Some(Memory::Base(base, displacement)))
+            }
+        };
+
+        self.code.extend(prefix);
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3) << 2 | (rm_code >> 3);
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+        self.code.extend_from_slice(opcode);
+
+        let reg = (reg & 0x07) << 3;
+        match memory {
+            None => self.code.push(0xC0 | reg | (rm_code & 0x07)),
+            Some(Memory::Rip(target)) => {
+                self.code.push(reg | 0x05);
+                return self.relative(target);
+            }
+            Some(Memory::Base(base, displacement)) => {
+                let base = base as u8 & 0x07;
+                // rbp and r13 can't be encoded without a displacement
+                let mode = match displacement {
+                    0 if base != 5 => 0x00,
+                    -128..=127 => 0x40,
+                    _ => 0x80,
+                };
+                self.code.push(mode | reg | base);
+                // rsp and r12 need a SIB byte
+                if base == 4 {
+                    self.code.push(0x24);
+                }
+                match mode {
+                    0x40 => self.code.push(displacement as i8 as u8),
+                    0x80 => self.code.extend_from_slice(&displacement.to_le_bytes()),
+                    _ => {}
+                }
+            }
+        }
+        self
+    }
+
+    fn relative(&mut self, target: Target) -> &mut Self {
+        self.fixups.push((self.code.len(), target));
+        self.code.extend_from_slice(&[0; 4]);
+        self
+    }
+
+    fn invalid(&mut self, name: &str, destination: Operand, source: Operand) -> &mut Self {
+        self.fail(anyhow!(
+            "Invalid operands for {name}: {destination:?}, {source:?}"
+        ))
+    }
+
+    fn fail(&mut self, error: anyhow::Error) -> &mut Self {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+        self
+    }
+}
+
+impl From<Register> for Operand {
+    fn from(register: Register) -> Self {
+        Operand::Register(register)
+    }
+}
+
+impl From<Xmm> for Operand {
+    fn from(xmm: Xmm) -> Self {
+        Operand::Xmm(xmm)
+    }
+}
+
+impl From<Memory> for Operand {
+    fn from(memory: Memory) -> Self {
+        Operand::Memory(memory)
+    }
+}
+
+impl From<Label> for Operand {
+    fn from(label: Label) -> Self {
+        Operand::Memory(Memory::Rip(Target::Label(label)))
+    }
+}
+
+impl From<Label> for Target {
+    fn from(label: Label) -> Self {
+        Target::Label(label)
+    }
+}
+
+impl From<usize> for Target {
+    fn from(address: usize) -> Self {
+        Target::Address(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Register::*;
+    use Xmm::*;
+
+    fn assemble(build: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut assembler = Assembler::new(0x1000);
+        build(&mut assembler);
+        assembler.finish().unwrap()
+    }
+
+    #[test]
+    fn encodes_mov() {
+        let mov = |destination: Operand, source: Operand| {
+            assemble(|assembler| {
+                assembler.mov(destination, source);
+            })
+        };
+        assert_eq!(
+            mov(Rax.into(), Memory::Base(Rsp, 8).into()),
+            [0x48, 0x8B, 0x44, 0x24, 0x08]
+        );
+        assert_eq!(
+            mov(R12.into(), Memory::Base(R13, 0).into()),
+            [0x4D, 0x8B, 0x65, 0x00]
+        );
+        assert_eq!(
+            mov(Rax.into(), Memory::Base(Rbx, 0).into()),
+            [0x48, 0x8B, 0x03]
+        );
+        assert_eq!(mov(Rax.into(), Rcx.into()), [0x48, 0x8B, 0xC1]);
+        assert_eq!(
+            mov(Memory::Base(Rsp, 0x10).into(), Rcx.into()),
+            [0x48, 0x89, 0x4C, 0x24, 0x10]
+        );
+        assert_eq!(
+            mov(Memory::Base(Rdx, -0x200).into(), R9.into()),
+            [0x4C, 0x89, 0x8A, 0x00, 0xFE, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            assemble(|assembler| {
+                assembler.mov_immediate(R11, 0x1122_3344_5566_7788);
+            }),
+            [0x49, 0xBB, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+        );
+    }
+
+    #[test]
+    fn encodes_every_register() {
+        let registers = [
+            Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi, R8, R9, R10, R11, R12, R13, R14, R15,
+        ];
+        for (index, register) in registers.into_iter().enumerate() {
+            let code = assemble(|assembler| {
+                assembler.mov(register, Rax);
+            });
+            let rex = 0x48 | ((index as u8 >> 3) << 2);
+            assert_eq!(
+                code,
+                [rex, 0x8B, 0xC0 | ((index as u8 & 7) << 3)],
+                "{register:?}"
+            );
+        }
+
+        let registers = [
+            Xmm0, Xmm1, Xmm2, Xmm3, Xmm4, Xmm5, Xmm6, Xmm7, Xmm8, Xmm9, Xmm10, Xmm11, Xmm12, Xmm13,
+            Xmm14, Xmm15,
+        ];
+        for (index, register) in registers.into_iter().enumerate() {
+            let code = assemble(|assembler| {
+                assembler.movss(register, Xmm0);
+            });
+            let mut expected = vec![0xF3];
+            if index >= 8 {
+                expected.push(0x44);
+            }
+            expected.extend_from_slice(&[0x0F, 0x10, 0xC0 | ((index as u8 & 7) << 3)]);
+            assert_eq!(code, expected, "{register:?}");
+        }
+    }
+
+    #[test]
+    fn encodes_scalar_floats() {
+        assert_eq!(
+            assemble(|assembler| {
+                assembler.movss(Xmm8, Memory::Base(Rax, 0));
+            }),
+            [0xF3, 0x44, 0x0F, 0x10, 0x00]
+        );
+        assert_eq!(
+            assemble(|assembler| {
+                assembler.movss(Memory::Base(R12, 0x100), Xmm15);
+            }),
+            [0xF3, 0x45, 0x0F, 0x11, 0xBC, 0x24, 0x00, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(
+            assemble(|assembler| {
+                assembler
+                    .mulss(Xmm1, Xmm2)
+                    .addss(Xmm9, Memory::Base(Rbp, 4));
+            }),
+            [0xF3, 0x0F, 0x59, 0xCA, 0xF3, 0x44, 0x0F, 0x58, 0x4D, 0x04]
+        );
+    }
+
+    #[test]
+    fn fixes_up_labels_and_targets() {
+        let code = assemble(|assembler| {
+            let value = assembler.label();
+            assembler
+                .movss(Xmm4, value)
+                .call(0x3000)
+                .jmp(0x2000)
+                .nop()
+                .bind(value)
+                .f32(1.5);
+        });
+        let mut expected = vec![0xF3, 0x0F, 0x10, 0x25, 0x0B, 0x00, 0x00, 0x00];
+        expected.extend_from_slice(&[0xE8, 0xF3, 0x1F, 0x00, 0x00]);
+        expected.extend_from_slice(&[0xE9, 0xEE, 0x0F, 0x00, 0x00]);
+        expected.push(0x90);
+        expected.extend_from_slice(&1.5f32.to_le_bytes());
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn reports_errors_at_finish() {
+        let mut assembler = Assembler::new(0x1000);
+        assembler.movss(Rax, Xmm0).nop();
+        assert_eq!(
+            assembler.finish().unwrap_err().to_string(),
+            "Invalid operands for movss: Register(Rax), Xmm(Xmm0)"
+        );
+
+        let mut assembler = Assembler::new(0x1000);
+        let label = assembler.label();
+        assembler.jmp(label);
+        assert_eq!(
+            assembler.finish().unwrap_err().to_string(),
+            "Label 0 is never bound"
+        );
+
+        let mut assembler = Assembler::new(0x1000);
+        assembler.jmp(0x1_0000_2000);
+        assert!(assembler.finish().is_err());
+    }
+}
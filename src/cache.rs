@@ -0,0 +1,115 @@
+use crate::version::Fingerprint;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const CACHE_PATH: &str = "mirage-tweaks-cache.toml";
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    #[serde(default)]
+    builds: Vec<CachedBuild>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedBuild {
+    timestamp: u32,
+    #[serde(rename = "image-size")]
+    image_size: u32,
+    hash: Option<String>,
+    // Offsets from the main module base, by section and signature
+    #[serde(default)]
+    offsets: HashMap<String, Vec<usize>>,
+}
+
+// Signature matches of one game build, so the next launch can check them instead of scanning
+pub struct ScanCache {
+    build: CachedBuild,
+    changed: bool,
+}
+
+impl ScanCache {
+    pub fn load(fingerprint: &Fingerprint) -> Self {
+        let build = CachedBuild {
+            timestamp: fingerprint.timestamp,
+            image_size: fingerprint.image_size,
+            hash: fingerprint.hash.map(|hash| format!("{hash:016X}")),
+            offsets: HashMap::new(),
+        };
+        let build = read()
+            .builds
+            .into_iter()
+            .find(|cached| cached.same_build(&build))
+            .unwrap_or(build);
+        if !build.offsets.is_empty() {
+            info!(
+                "Loaded {} cached signature matches from {CACHE_PATH}",
+                build.offsets.len()
+            );
+        }
+        Self {
+            build,
+            changed: false,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[usize]> {
+        self.build.offsets.get(key).map(Vec::as_slice)
+    }
+
+    pub fn insert(&mut self, key: &str, offsets: Vec<usize>) {
+        if self.get(key) != Some(&offsets) {
+            self.build.offsets.insert(key.into(), offsets);
+            self.changed = true;
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if self.build.offsets.remove(key).is_some() {
+            self.changed = true;
+        }
+    }
+
+    pub fn save(&self) {
+        if !self.changed {
+            return;
+        }
+
+        let mut file = read();
+        file.builds.retain(|cached| !cached.same_build(&self.build));
+        file.builds.push(self.build.clone());
+        let cache = match toml::to_string(&file) {
+            Ok(cache) => cache,
+            Err(error) => {
+                warn!("Couldn't serialize scan cache to toml ({error})");
+                return;
+            }
+        };
+
+        if let Err(error) = std::fs::write(CACHE_PATH, cache) {
+            warn!("Couldn't write scan cache to {CACHE_PATH} ({error})");
+        }
+    }
+}
+
+impl CachedBuild {
+    fn same_build(&self, other: &CachedBuild) -> bool {
+        self.timestamp == other.timestamp
+            && self.image_size == other.image_size
+            && self.hash == other.hash
+    }
+}
+
+fn read() -> CacheFile {
+    let cache = match std::fs::read_to_string(CACHE_PATH) {
+        Ok(cache) => cache,
+        Err(_) => return Default::default(),
+    };
+    match toml::from_str(&cache) {
+        Ok(cache) => cache,
+        Err(error) => {
+            warn!("Couldn't parse {CACHE_PATH}, ignoring it ({error})");
+            Default::default()
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use crate::memory::MemoryBackend;
+use anyhow::Result;
+use log::info;
+use std::sync::{Arc, Mutex};
+
+const PADDING: u8 = 0xCC;
+const PADDING_SEARCH_SIZE: usize = 0x10000;
+const ALLOCATION_SIZE: usize = 0x1000;
+pub const REL32_RANGE: usize = 0x7FF0_0000;
+
+#[derive(Default)]
+pub struct CaveAllocator {
+    claimed: Vec<(usize, usize)>,
+    allocations: Vec<(usize, usize)>,
+}
+
+pub struct Cave {
+    allocator: Arc<Mutex<CaveAllocator>>,
+    pub address: usize,
+    pub size: usize,
+}
+
+impl CaveAllocator {
+    // Forgets the allocations once they are freed, claims in them must be released first
+    pub fn clear_allocations(&mut self) {
+        self.allocations.clear();
+    }
+
+    pub fn claim(
+        allocator: &Arc<Mutex<Self>>,
+        process: &dyn MemoryBackend,
+        near: usize,
+        size: usize,
+        alignment: usize,
+    ) -> Result<Cave> {
+        assert!(
+            alignment.is_power_of_two(),
+            "Code cave alignment {alignment} isn't a power of two"
+        );
+        let mut caves = allocator.lock().unwrap();
+
+        let address = match caves.find_padding(process, near, size, alignment) {
+            Some(address) => {
+                info!("Found {size} bytes of padding for a code cave at {address:#X}");
+                address
+            }
+            None => match caves.find_allocated(near, size, alignment) {
+                Some(address) => address,
+                None => {
+                    let allocation_size = ALLOCATION_SIZE.max(align(size, ALLOCATION_SIZE));
+                    let allocation = process.allocate(near, allocation_size)?;
+                    info!("Allocated {allocation_size:#X} bytes for code caves at {allocation:#X}");
+                    caves.allocations.push((allocation, allocation_size));
+                    allocation
+                }
+            },
+        };
+
+        caves.claimed.push((address, size));
+        Ok(Cave {
+            allocator: allocator.clone(),
+            address,
+            size,
+        })
+    }
+
+    fn find_padding(
+        &self,
+        process: &dyn MemoryBackend,
+        near: usize,
+        size: usize,
+        alignment: usize,
+    ) -> Option<usize> {
+        let region = process.regions(near).into_iter().next()?;
+        if !region.readable {
+            return None;
+        }
+        let end = (region.address + region.size).min(near + PADDING_SEARCH_SIZE);
+        let memory = process.read(near, end - near).ok()?;
+
+        let mut address = align(near, alignment);
+        while address + size <= end {
+            let offset = address - near;
+            if memory[offset..(offset + size)]
+                .iter()
+                .all(|&byte| byte == PADDING)
+                && self.is_free(address, size)
+            {
+                return Some(address);
+            }
+            address += alignment;
+        }
+        None
+    }
+
+    fn find_allocated(&self, near: usize, size: usize, alignment: usize) -> Option<usize> {
+        for &(allocation, allocation_size) in &self.allocations {
+            if allocation.abs_diff(near) > REL32_RANGE {
+                continue;
+            }
+
+            let mut address = allocation;
+            while address + size <= allocation + allocation_size {
+                if self.is_free(address, size) {
+                    return Some(address);
+                }
+                address += alignment;
+            }
+        }
+        None
+    }
+
+    fn is_free(&self, address: usize, size: usize) -> bool {
+        self.claimed.iter().all(|&(claimed, claimed_size)| {
+            address + size <= claimed || claimed + claimed_size <= address
+        })
+    }
+}
+
+impl Drop for Cave {
+    fn drop(&mut self) {
+        info!(
+            "Releasing {} byte code cave at {:#X}",
+            self.size, self.address
+        );
+        let mut caves = self.allocator.lock().unwrap();
+        let address = self.address;
+        caves.claimed.retain(|&(claimed, _)| claimed != address);
+    }
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    debug_assert!(alignment.is_power_of_two());
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+// Aligned addresses inside the free ranges that are within rel32 range of near, closest first
+pub fn allocation_candidates(
+    free: &[(usize, usize)],
+    near: usize,
+    size: usize,
+    granularity: usize,
+) -> Vec<usize> {
+    let low = near.saturating_sub(REL32_RANGE);
+    let high = near.saturating_add(REL32_RANGE);
+
+    let mut candidates = Vec::new();
+    for &(start, end) in free {
+        let start = start.max(low);
+        let end = end.min(high);
+        let first = align(start, granularity);
+        if first >= end || end - first < size {
+            continue;
+        }
+        candidates.push(first);
+        candidates.push((end - size) & !(granularity - 1));
+    }
+    candidates.sort_by_key(|candidate| candidate.abs_diff(near));
+    candidates.dedup();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BufferBackend;
+
+    const CODE: usize = 0x10000;
+
+    fn allocator() -> (Arc<Mutex<CaveAllocator>>, BufferBackend) {
+        let mut code = vec![0x90; 0x100];
+        code[0x21..0x40].fill(PADDING);
+        let process = BufferBackend::new(CODE).with_region(CODE, code);
+        (Default::default(), process)
+    }
+
+    #[test]
+    fn claims_aligned_padding_once() {
+        let (allocator, process) = allocator();
+        let first = CaveAllocator::claim(&allocator, &process, CODE, 8, 16).unwrap();
+        assert_eq!(first.address, CODE + 0x30);
+        let second = CaveAllocator::claim(&allocator, &process, CODE, 4, 4).unwrap();
+        assert_eq!(second.address, CODE + 0x24);
+
+        drop(first);
+        let third = CaveAllocator::claim(&allocator, &process, CODE, 8, 16).unwrap();
+        assert_eq!(third.address, CODE + 0x30);
+    }
+
+    #[test]
+    #[should_panic(expected = "alignment 0 isn't a power of two")]
+    fn rejects_zero_alignment() {
+        let (allocator, process) = allocator();
+        let _ = CaveAllocator::claim(&allocator, &process, CODE, 4, 0);
+    }
+
+    #[test]
+    fn orders_allocation_candidates_by_distance() {
+        let near = 0x1_0000_0000;
+        let free = [(0x7000_0000, 0x9000_0000), (0x1_0001_1000, 0x1_0004_0000)];
+        assert_eq!(
+            allocation_candidates(&free, near, 0x1000, 0x10000),
+            [0x1_0002_0000, 0x1_0003_0000, 0x8FFF_0000, 0x8010_0000]
+        );
+    }
+}
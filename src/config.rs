@@ -5,13 +5,21 @@ use std::collections::HashMap;
 use std::sync::Mutex;
 use toml::Value;
 
-pub static CONFIG: Lazy<Mutex<Config>> = Lazy::new(|| Mutex::new(Config::load()));
+// Tests start from the default config instead of the one next to the executable
+pub static CONFIG: Lazy<Mutex<Config>> = Lazy::new(|| {
+    Mutex::new(if cfg!(test) {
+        Config::default()
+    } else {
+        Config::load()
+    })
+});
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Config {
     #[serde(rename = "module-names")]
     pub module_names: Option<Vec<String>>,
     pub tweaks: Option<HashMap<String, TweakConfig>>,
+    pub addresses: Option<HashMap<String, String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,6 +48,10 @@ impl Config {
         }
     }
 
+    pub fn address(&self, key: &str) -> Option<String> {
+        self.addresses.as_ref()?.get(key).cloned()
+    }
+
     pub fn save(&self) {
         let config = match toml::to_string(self) {
             Ok(config) => config,
@@ -1,49 +1,636 @@
-use crate::process::Process;
-use anyhow::Result;
+use crate::address::AddressExpression;
+use crate::cache::ScanCache;
+use crate::cave::{Cave, CaveAllocator};
+use crate::config::CONFIG;
+use crate::memory::{bytes_of, MemoryBackend};
+use crate::process::{Candidate, Process};
+#[cfg(test)]
+use crate::registry::PatchRecord;
+use crate::registry::PatchRegistry;
+use crate::version::{self, Fingerprint, Version, VERSIONS};
+use crate::x86::{self, MAX_INSTRUCTION_LENGTH};
+use anyhow::{anyhow, bail, Result};
 use log::{info, warn};
+use std::any::Any;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[cfg(test)]
+const JUMP_SIZE: usize = 5;
+#[cfg(test)]
+const NOP: u8 = 0x90;
+
+static NEXT_PATCH_SET: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Clone)]
 pub struct Game {
-    pub process: Arc<Process>,
+    pub process: Arc<dyn MemoryBackend>,
+    caves: Arc<Mutex<CaveAllocator>>,
+    registry: Arc<Mutex<PatchRegistry>>,
+    owner: &'static str,
+    fingerprint: Option<Fingerprint>,
 }
 
 impl Game {
-    pub fn attach() -> Result<Self> {
-        let process = Arc::new(Process::attach()?);
-        Ok(Self { process })
+    pub fn attach(candidate: &Candidate) -> Result<Self> {
+        let process: Arc<dyn MemoryBackend> = Arc::new(Process::open(candidate)?);
+        match process.modules() {
+            Ok(modules) => {
+                let base = process.module_base();
+                if let Some(module) = modules.iter().find(|module| module.base == base) {
+                    info!(
+                        "Found {} modules, main module is {}",
+                        modules.len(),
+                        module.path
+                    );
+                }
+            }
+            Err(error) => warn!("Couldn't list modules ({error})"),
+        }
+
+        let fingerprint = match Fingerprint::read(process.as_ref()) {
+            Ok(fingerprint) => {
+                info!(
+                    "Game build has timestamp = {:#X}, image-size = {:#X}, hash = \"{}\"",
+                    fingerprint.timestamp,
+                    fingerprint.image_size,
+                    fingerprint
+                        .hash
+                        .map_or("unknown".into(), |hash| format!("{hash:016X}"))
+                );
+                Some(fingerprint)
+            }
+            Err(error) => {
+                warn!("Couldn't fingerprint the game executable ({error})");
+                None
+            }
+        };
+
+        Ok(Self {
+            fingerprint,
+            ..Self::new(process)
+        })
+    }
+
+    pub fn new(process: Arc<dyn MemoryBackend>) -> Self {
+        Self {
+            process,
+            caves: Default::default(),
+            registry: Default::default(),
+            owner: "Mirage Tweaks",
+            fingerprint: None,
+        }
+    }
+
+    pub fn owned_by(&self, owner: &'static str) -> Self {
+        Self {
+            owner,
+            ..self.clone()
+        }
+    }
+
+    #[cfg(test)]
+    pub fn patches(&self) -> Vec<PatchRecord> {
+        self.registry.lock().unwrap().records().to_vec()
+    }
+
+    // The memory of an exited game is gone, so there is nothing left to restore
+    pub fn forget_patches(&self) {
+        let records = self.registry.lock().unwrap().take_all();
+        if !records.is_empty() {
+            info!("Forgetting {} patches of the exited game", records.len());
+        }
+    }
+
+    pub fn restore_all(&self) {
+        let records = self.registry.lock().unwrap().take_all();
+        for mut record in records.into_iter().rev() {
+            info!(
+                "Restoring patch at {:#X} owned by {}",
+                record.address, record.owner
+            );
+            if let Err(error) = record.restore(self.process.as_ref()) {
+                warn!("Couldn't restore patch ({error})");
+            }
+        }
+    }
+
+    pub fn version(&self) -> Option<&'static Version> {
+        self.fingerprint.as_ref()?.version()
+    }
+
+    pub fn check_version(&self) -> Result<()> {
+        if let Some(version) = version::check(self.fingerprint.as_ref(), &VERSIONS)? {
+            info!(
+                "Game version is {}, tested on {}",
+                version.name, version.tested
+            );
+        }
+        Ok(())
+    }
+
+    pub fn scan_cache(&self) -> Option<ScanCache> {
+        self.fingerprint.as_ref().map(ScanCache::load)
+    }
+
+    // Address expression from the config, or from the version table for this build
+    pub fn address(&self, key: &str) -> Option<String> {
+        let address = CONFIG.lock().unwrap().address(key);
+        address.or_else(|| self.version()?.addresses.get(key).cloned())
+    }
+
+    pub fn signature(&self, key: &str, default: &str) -> String {
+        match self
+            .version()
+            .and_then(|version| version.signatures.get(key))
+        {
+            Some(signature) => signature.clone(),
+            None => default.into(),
+        }
+    }
+
+    // Restores every patch and frees the memory allocated for code caves
+    pub fn detach(&self) {
+        self.restore_all();
+        self.caves.lock().unwrap().clear_allocations();
+        self.process.free_allocations();
+    }
+
+    pub fn resolve(&self, expression: &str) -> Result<usize> {
+        AddressExpression::parse(expression)?.resolve(self)
+    }
+
+    pub fn module_address(&self, module: &str, offset: usize) -> Result<usize> {
+        let module = self.process.module(module)?;
+        if offset >= module.size {
+            bail!(
+                "Offset {offset:#X} is outside of {} ({:#X} bytes)",
+                module.name,
+                module.size
+            );
+        }
+        Ok(module.base + offset)
+    }
+
+    pub fn cave(&self, near: usize, size: usize, alignment: usize) -> Result<Cave> {
+        CaveAllocator::claim(&self.caves, self.process.as_ref(), near, size, alignment)
     }
 
     pub fn patch<T: Debug>(&self, address: usize, value: &T, original: T) -> Result<Patch<T>> {
+        self.verify_original(address, &original)?;
         info!("Patching {address:#X} with {value:X?}");
-        self.process.write(address, value)?;
-        Ok(Patch {
+        let id = self.apply(address, bytes_of(&original), bytes_of(value), false)?;
+        Ok(self.guard(id, address))
+    }
+
+    pub fn patch_in_cave<T: Debug>(&self, cave: Cave, value: &T) -> Result<Patch<T>> {
+        if cave.size < size_of::<T>() {
+            bail!(
+                "{} bytes don't fit the {} byte code cave at {:#X}",
+                size_of::<T>(),
+                cave.size,
+                cave.address
+            );
+        }
+        let original = self.process.read_into(cave.address)?;
+        let mut patch = self.patch(cave.address, value, original)?;
+        patch._cave = Some(cave);
+        Ok(patch)
+    }
+
+    pub fn patch_set(&self) -> PatchSet {
+        PatchSet {
+            game: self.clone(),
+            id: NEXT_PATCH_SET.fetch_add(1, Ordering::Relaxed),
+            staged: Vec::new(),
+        }
+    }
+
+    pub fn patch_code<T: Debug>(&self, address: usize, value: &T, original: T) -> Result<Patch<T>> {
+        self.verify_original(address, &original)?;
+        self.check_boundaries(address, bytes_of(value))?;
+
+        info!("Patching code at {address:#X} with {value:X?}");
+        let id = self.apply(address, bytes_of(&original), bytes_of(value), true)?;
+        Ok(self.guard(id, address))
+    }
+
+    // No tweak hooks code yet, the stub is built once for the code cave it runs in and may use up to stub_size bytes
+    #[cfg(test)]
+    pub fn hook(
+        &self,
+        address: usize,
+        stub_size: usize,
+        stub: impl FnOnce(usize) -> Result<Vec<u8>>,
+    ) -> Result<Hook> {
+        let code = self
+            .process
+            .read(address, JUMP_SIZE + MAX_INSTRUCTION_LENGTH)?;
+        let length = x86::covering_length(&code, JUMP_SIZE)?;
+        let original = code[..length].to_vec();
+
+        // The stub runs first, then the overwritten instructions, then execution continues after them
+        let relocated_size = x86::relocate(&original, address, address)?.len();
+        let size = stub_size + relocated_size + JUMP_SIZE;
+        let cave = self.cave(address, size, 16)?;
+        let mut trampoline = stub(cave.address)?;
+        if trampoline.len() > stub_size {
+            bail!(
+                "Hook stub for {address:#X} is {} bytes, expected at most {stub_size}",
+                trampoline.len()
+            );
+        }
+        let relocated = x86::relocate(&original, address, cave.address + trampoline.len())?;
+        trampoline.extend_from_slice(&relocated);
+        let jump_back = cave.address + trampoline.len();
+        trampoline.extend_from_slice(&x86::jump(jump_back, address + length)?);
+
+        let mut jump = x86::jump(address, cave.address)?.to_vec();
+        jump.resize(length, NOP);
+
+        info!(
+            "Hooking {address:#X} with a {} byte trampoline at {:#X}",
+            trampoline.len(),
+            cave.address
+        );
+        let cave_original = self.process.read(cave.address, trampoline.len())?;
+        self.process.write_bytes(cave.address, &trampoline)?;
+        let id = match self.apply(address, &original, &jump, true) {
+            Ok(id) => id,
+            Err(error) => {
+                if let Err(error) = self.process.write_bytes(cave.address, &cave_original) {
+                    warn!(
+                        "Couldn't restore code cave at {:#X} ({error})",
+                        cave.address
+                    );
+                }
+                return Err(error);
+            }
+        };
+
+        Ok(Hook {
             process: self.process.clone(),
+            registry: self.registry.clone(),
+            id,
             address,
-            original,
+            cave_original,
+            cave,
         })
     }
+
+    fn apply(&self, address: usize, original: &[u8], value: &[u8], code: bool) -> Result<usize> {
+        let mut registry = self.registry.lock().unwrap();
+        registry.check(self.owner, address, value.len())?;
+        if code {
+            self.process.write_code_bytes(address, value)?;
+        } else {
+            self.process.write_bytes(address, value)?;
+        }
+        Ok(registry.register(self.owner, address, original.to_vec(), value.to_vec(), code))
+    }
+
+    fn guard<T>(&self, id: usize, address: usize) -> Patch<T> {
+        Patch {
+            process: self.process.clone(),
+            registry: self.registry.clone(),
+            id,
+            address,
+            _cave: None,
+            _type: PhantomData,
+        }
+    }
+
+    fn verify_original<T: Debug>(&self, address: usize, original: &T) -> Result<()> {
+        let live = self.process.read(address, size_of::<T>())?;
+        if live != bytes_of(original) {
+            bail!(
+                "Expected {original:X?} at {address:#X} but found {live:02X?}, refusing to patch"
+            );
+        }
+        Ok(())
+    }
+
+    fn check_boundaries(&self, address: usize, replacement: &[u8]) -> Result<()> {
+        let size = replacement.len();
+        let code = self.process.read(address, size + MAX_INSTRUCTION_LENGTH)?;
+        let length = x86::covering_length(&code, size)?;
+        if length != size {
+            bail!(
+                "Patching {size} bytes at {address:#X} would split an instruction ({length} bytes are covered)"
+            );
+        }
+        if x86::covering_length(replacement, size)? != size {
+            bail!("Patch for {address:#X} doesn't end on an instruction boundary");
+        }
+        Ok(())
+    }
 }
 
 pub struct Patch<T> {
-    process: Arc<Process>,
+    process: Arc<dyn MemoryBackend>,
+    registry: Arc<Mutex<PatchRegistry>>,
+    id: usize,
     address: usize,
-    original: T,
+    _cave: Option<Cave>,
+    _type: PhantomData<T>,
 }
 
 impl<T: Debug> Patch<T> {
     pub fn update(&self, value: &T) -> Result<()> {
         info!("Updating patch at {:#X} to {value:X?}", self.address);
-        self.process.write(self.address, value)
+        let mut registry = self.registry.lock().unwrap();
+        let Some(record) = registry.get_mut(self.id) else {
+            bail!("Patch at {:#X} was already restored", self.address);
+        };
+        record.write(self.process.as_ref(), bytes_of(value))
     }
 }
 
 impl<T> Drop for Patch<T> {
     fn drop(&mut self) {
-        info!("Restoring patch at {:#X}", self.address);
-        if let Err(error) = self.process.write(self.address, &self.original) {
-            warn!("Couldn't restore patch ({error})");
+        // Only restores if the game hasn't changed our patched bytes, e.g. by freeing and reusing the memory
+        let record = self.registry.lock().unwrap().remove(self.id);
+        if let Some(mut record) = record {
+            info!("Restoring patch at {:#X}", self.address);
+            if let Err(error) = record.restore(self.process.as_ref()) {
+                warn!("Couldn't restore patch ({error})");
+            }
+        }
+    }
+}
+
+type StagedPatch = Box<dyn FnOnce(&Game) -> Result<Box<dyn Any + Send>> + Send>;
+
+pub struct PatchSet {
+    game: Game,
+    id: usize,
+    staged: Vec<StagedPatch>,
+}
+
+pub struct PatchHandle<T> {
+    set: usize,
+    index: usize,
+    _type: PhantomData<T>,
+}
+
+// Applied patches, restored in reverse order when dropped
+pub struct Patches {
+    set: usize,
+    patches: Vec<Box<dyn Any + Send>>,
+}
+
+impl PatchSet {
+    pub fn patch<T: Debug + Send + 'static>(
+        &mut self,
+        address: usize,
+        value: T,
+        original: T,
+    ) -> PatchHandle<T> {
+        self.stage(move |game| game.patch(address, &value, original))
+    }
+
+    pub fn patch_code<T: Debug + Send + 'static>(
+        &mut self,
+        address: usize,
+        value: T,
+        original: T,
+    ) -> PatchHandle<T> {
+        self.stage(move |game| game.patch_code(address, &value, original))
+    }
+
+    pub fn patch_in_cave<T: Debug + Send + 'static>(
+        &mut self,
+        cave: Cave,
+        value: T,
+    ) -> PatchHandle<T> {
+        self.stage(move |game| game.patch_in_cave(cave, &value))
+    }
+
+    pub fn apply(self) -> Result<Patches> {
+        let mut patches = Patches {
+            set: self.id,
+            patches: Vec::with_capacity(self.staged.len()),
+        };
+        for apply in self.staged {
+            match apply(&self.game) {
+                Ok(patch) => patches.patches.push(patch),
+                Err(error) => {
+                    if !patches.patches.is_empty() {
+                        warn!("Rolling back {} applied patches", patches.patches.len());
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok(patches)
+    }
+
+    fn stage<T: Send + 'static>(
+        &mut self,
+        apply: impl FnOnce(&Game) -> Result<Patch<T>> + Send + 'static,
+    ) -> PatchHandle<T> {
+        self.staged.push(Box::new(move |game| {
+            Ok(Box::new(apply(game)?) as Box<dyn Any + Send>)
+        }));
+        PatchHandle {
+            set: self.id,
+            index: self.staged.len() - 1,
+            _type: PhantomData,
+        }
+    }
+}
+
+impl Patches {
+    pub fn get<T: 'static>(&self, handle: &PatchHandle<T>) -> Result<&Patch<T>> {
+        if handle.set != self.set {
+            bail!("Patch handle belongs to a different patch set");
+        }
+        self.patches
+            .get(handle.index)
+            .and_then(|patch| patch.downcast_ref())
+            .ok_or_else(|| anyhow!("Patch handle doesn't match a patch of this set"))
+    }
+}
+
+impl Drop for Patches {
+    fn drop(&mut self) {
+        while let Some(patch) = self.patches.pop() {
+            drop(patch);
         }
     }
 }
+
+#[cfg(test)]
+pub struct Hook {
+    process: Arc<dyn MemoryBackend>,
+    registry: Arc<Mutex<PatchRegistry>>,
+    id: usize,
+    address: usize,
+    cave_original: Vec<u8>,
+    cave: Cave,
+}
+
+#[cfg(test)]
+impl Drop for Hook {
+    fn drop(&mut self) {
+        info!("Removing hook at {:#X}", self.address);
+        // Leave the trampoline in place if the jump wasn't removed, the game may still use it
+        let Some(mut record) = self.registry.lock().unwrap().remove(self.id) else {
+            return;
+        };
+        if let Err(error) = record.restore(self.process.as_ref()) {
+            warn!("Couldn't remove hook ({error})");
+            return;
+        }
+        if let Err(error) = self
+            .process
+            .write_code_bytes(self.cave.address, &self.cave_original)
+        {
+            warn!("Couldn't restore code cave ({error})");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BufferBackend;
+
+    const ADDRESS: usize = 0x10000;
+
+    fn game() -> Game {
+        let mut data = vec![0; 0x100];
+        data[..4].copy_from_slice(&1.5f32.to_le_bytes());
+        Game::new(Arc::new(
+            BufferBackend::new(ADDRESS).with_region(ADDRESS, data),
+        ))
+    }
+
+    #[test]
+    fn patch_restores_on_drop() {
+        let game = game();
+        let patch = game.patch(ADDRESS, &3.0f32, 1.5).unwrap();
+        assert_eq!(game.process.read_into::<f32>(ADDRESS).unwrap(), 3.0);
+        patch.update(&4.0).unwrap();
+        assert_eq!(game.process.read_into::<f32>(ADDRESS).unwrap(), 4.0);
+
+        drop(patch);
+        assert_eq!(game.process.read_into::<f32>(ADDRESS).unwrap(), 1.5);
+    }
+
+    #[test]
+    fn patch_set_rolls_back_on_failure() {
+        let game = game();
+        let mut patch_set = game.patch_set();
+        patch_set.patch(ADDRESS, 3.0f32, 1.5);
+        patch_set.patch(ADDRESS + 4, 1.0f32, 2.0);
+        let error = patch_set.apply().err().unwrap();
+        assert!(error.to_string().starts_with("Expected 2.0 at 0x10004"));
+        assert_eq!(game.process.read_into::<f32>(ADDRESS).unwrap(), 1.5);
+        assert!(game.patches().is_empty());
+
+        let mut patch_set = game.patch_set();
+        let first = patch_set.patch(ADDRESS, 3.0f32, 1.5);
+        let second = patch_set.patch(ADDRESS + 4, 1.0f32, 0.0);
+        let patches = patch_set.apply().unwrap();
+        patches.get(&first).unwrap().update(&4.0).unwrap();
+        patches.get(&second).unwrap().update(&5.0).unwrap();
+        let other = game.patch_set().patch(ADDRESS, 1.0f32, 1.5);
+        assert!(patches.get(&other).is_err());
+        assert_eq!(
+            game.process.read_into::<[f32; 2]>(ADDRESS).unwrap(),
+            [4.0, 5.0]
+        );
+
+        drop(patches);
+        assert_eq!(
+            game.process.read_into::<[f32; 2]>(ADDRESS).unwrap(),
+            [1.5, 0.0]
+        );
+    }
+
+    #[test]
+    fn restore_all_after_rejecting_overlaps() {
+        let game = game();
+        let first = game.owned_by("First");
+        let second = game.owned_by("Second");
+        let patch = first.patch(ADDRESS, &3.0f32, 1.5).unwrap();
+        assert!(second.patch(ADDRESS + 2, &0u16, 0).is_err());
+        let other = second.patch(ADDRESS + 4, &2.0f32, 0.0).unwrap();
+        assert_eq!(game.patches().len(), 2);
+
+        game.restore_all();
+        assert_eq!(
+            game.process.read_into::<[f32; 2]>(ADDRESS).unwrap(),
+            [1.5, 0.0]
+        );
+        assert!(game.patches().is_empty());
+        assert!(patch.update(&4.0).is_err());
+        drop((patch, other));
+        assert_eq!(
+            game.process.read_into::<[f32; 2]>(ADDRESS).unwrap(),
+            [1.5, 0.0]
+        );
+    }
+
+    #[test]
+    fn detach_restores_and_frees_caves() {
+        let game = game();
+        let cave = game.cave(ADDRESS, 4, 4).unwrap();
+        let cave_address = cave.address;
+        let _cave_patch = game.patch_in_cave(cave, &2.0f32).unwrap();
+        let _patch = game.patch(ADDRESS, &3.0f32, 1.5).unwrap();
+        assert_eq!(game.process.read_into::<f32>(cave_address).unwrap(), 2.0);
+
+        game.detach();
+        assert_eq!(game.process.read_into::<f32>(ADDRESS).unwrap(), 1.5);
+        assert!(game.process.read(cave_address, 4).is_err());
+        assert!(game.patches().is_empty());
+    }
+
+    #[test]
+    fn hook_relocates_and_restores() {
+        use crate::assembler::{Assembler, Memory, Xmm};
+
+        // sub rsp, 0x28; call 0x10080; ret; followed by padding
+        let code = [0x48, 0x83, 0xEC, 0x28, 0xE8, 0x77, 0x00, 0x00, 0x00, 0xC3];
+        let mut data = code.to_vec();
+        data.resize(0x100, 0xCC);
+        let game = Game::new(Arc::new(
+            BufferBackend::new(ADDRESS).with_region(ADDRESS, data),
+        ));
+        let process = game.process.as_ref();
+
+        // movss xmm0, [0x100F0]
+        let hook = game
+            .hook(ADDRESS, 8, |address| {
+                let mut assembler = Assembler::new(address);
+                assembler.movss(Xmm::Xmm0, Memory::Rip((ADDRESS + 0xF0).into()));
+                assembler.finish()
+            })
+            .unwrap();
+        let cave = hook.cave.address;
+
+        let mut jump = x86::jump(ADDRESS, cave).unwrap().to_vec();
+        jump.extend_from_slice(&[NOP; 4]);
+        assert_eq!(process.read(ADDRESS, 9).unwrap(), jump);
+
+        let mut trampoline = vec![0xF3, 0x0F, 0x10, 0x05];
+        trampoline.extend_from_slice(&x86::rel32(cave + 8, ADDRESS + 0xF0).unwrap());
+        trampoline.extend_from_slice(&[0x48, 0x83, 0xEC, 0x28, 0xE8]);
+        trampoline.extend_from_slice(&x86::rel32(cave + 17, ADDRESS + 0x80).unwrap());
+        trampoline.extend_from_slice(&x86::jump(cave + 17, ADDRESS + 9).unwrap());
+        assert_eq!(process.read(cave, trampoline.len()).unwrap(), trampoline);
+
+        drop(hook);
+        assert_eq!(process.read(ADDRESS, code.len()).unwrap(), code);
+        assert_eq!(
+            process.read(cave, trampoline.len()).unwrap(),
+            vec![0xCC; trampoline.len()]
+        );
+        assert!(game.patches().is_empty());
+    }
+}
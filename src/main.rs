@@ -1,41 +1,66 @@
 use crate::game::Game;
 use crate::logger::set_logger;
 use crate::menu::{Menu, SliderControl, State, Status};
+use crate::process::{Candidate, Process};
+use crate::scanner::{PatternNotFound, Scanner};
 use crate::tweaks::eject_height::EjectHeightTweak;
 use crate::tweaks::sprint_speed::SprintSpeedTweak;
-use log::error;
+use crate::tweaks::Tweak;
+use anyhow::Result;
+use log::{error, info, warn};
 use std::process::ExitCode;
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(windows)]
 use windows::Win32::System::Console::{FreeConsole, GetConsoleProcessList};
 
+mod address;
+#[cfg(test)]
+mod assembler;
+mod cache;
+mod cave;
 mod config;
 mod game;
 mod logger;
+mod memory;
 mod menu;
+mod pe;
 mod process;
+mod registry;
+mod scanner;
+mod signature;
+#[cfg(test)]
+mod test_util;
 mod tweaks;
+mod version;
+mod x86;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+const ATTACH_INTERVAL: Duration = Duration::from_secs(2);
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+const LOAD_ATTEMPTS: u32 = 6;
 
 fn main() -> ExitCode {
     let _guard = set_logger();
 
-    let game = match Game::attach() {
-        Ok(game) => game,
-        Err(error) => {
-            error!("Failed to attach to game: {error}");
-            show_error(error);
-            return ExitCode::FAILURE;
-        }
-    };
-
     let state = Arc::new(Mutex::new(State {
         controls: Vec::new(),
-        status: Status::Loading,
+        status: Status::Waiting,
+        choice: None,
+        notice: None,
+        error: None,
     }));
 
-    start_loading_tweaks(game, &state);
+    let current = Arc::new(Mutex::new(None));
+    start_loading_tweaks(&current, &state);
+
+    let result = Menu::show(state);
+    if let Some(game) = current.lock().unwrap().as_ref() {
+        game.detach();
+    }
 
-    match Menu::show(state) {
+    match result {
         Ok(_) => ExitCode::SUCCESS,
         Err(error) => {
             error!("Failed to show menu: {error}");
@@ -45,40 +70,248 @@ fn main() -> ExitCode {
     }
 }
 
-fn start_loading_tweaks(game: Game, state: &Arc<Mutex<State>>) {
+fn start_loading_tweaks(current: &Arc<Mutex<Option<Game>>>, state: &Arc<Mutex<State>>) {
+    let current = current.clone();
     let state = state.clone();
-    std::thread::spawn(move || {
-        std::thread::scope(|scope| {
-            scope.spawn(|| {
-                let mut eject_height_tweak = EjectHeightTweak::new(&game);
-                match &mut eject_height_tweak {
-                    Ok(tweak) => tweak.load_config(),
-                    Err(error) => error!("Failed to create Eject Height tweak: {error}"),
-                }
-                state
-                    .lock()
-                    .unwrap()
-                    .controls
-                    .push(Box::new(SliderControl::new(eject_height_tweak)));
-            });
-            scope.spawn(|| {
-                let mut sprint_speed_tweak = SprintSpeedTweak::new(&game);
-                match &mut sprint_speed_tweak {
-                    Ok(tweak) => tweak.load_config(),
-                    Err(error) => error!("Failed to create Sprint Speed tweak: {error}"),
-                }
-                state
-                    .lock()
-                    .unwrap()
-                    .controls
-                    .push(Box::new(SliderControl::new(sprint_speed_tweak)));
-            });
-        });
+    let mut requested = requested_pid();
+    thread::spawn(move || loop {
+        let game = wait_for_game(&mut requested, &state);
+        *current.lock().unwrap() = Some(game.clone());
+
+        let controls = {
+            let mut state = state.lock().unwrap();
+            state.status = Status::Loading;
+            std::mem::take(&mut state.controls)
+        };
+        drop(controls);
+        check_version(&game, &state);
+        let mut loader = TweakLoader::new();
+        loader.load(&game, &state);
         hide_console();
         state.lock().unwrap().status = Status::Done;
+
+        let mut scanned = Instant::now();
+        while game.process.is_alive() {
+            thread::sleep(WATCH_INTERVAL);
+            if !loader.done() && scanned.elapsed() >= loader.delay() {
+                loader.load(&game, &state);
+                scanned = Instant::now();
+            }
+        }
+        info!("Game exited, waiting for it to restart");
+        game.forget_patches();
+        state.lock().unwrap().status = Status::Detached;
     });
 }
 
+fn wait_for_game(requested: &mut Option<u32>, state: &Mutex<State>) -> Game {
+    let mut last_error = None;
+    loop {
+        let result = Process::candidates().and_then(|candidates| {
+            match choose(&candidates, requested, state) {
+                Some(candidate) => Game::attach(&candidate).map(Some),
+                None => Ok(None),
+            }
+        });
+        match result {
+            Ok(Some(game)) => {
+                state.lock().unwrap().error = None;
+                return game;
+            }
+            Ok(None) => {
+                if last_error.take().is_some() {
+                    state.lock().unwrap().error = None;
+                }
+            }
+            Err(error) => {
+                let error = error.to_string();
+                if last_error.as_ref() != Some(&error) {
+                    warn!("Couldn't attach to game ({error})");
+                    state.lock().unwrap().error =
+                        Some(format!("Couldn't attach to game ({error})"));
+                    last_error = Some(error);
+                }
+            }
+        }
+        thread::sleep(ATTACH_INTERVAL);
+    }
+}
+
+fn choose(
+    candidates: &[Candidate],
+    requested: &mut Option<u32>,
+    state: &Mutex<State>,
+) -> Option<Candidate> {
+    if let Some(pid) = requested.take() {
+        match candidates.iter().find(|candidate| candidate.pid == pid) {
+            Some(candidate) => return Some(candidate.clone()),
+            None => warn!("Process {pid} isn't a running game instance, ignoring --pid"),
+        }
+    }
+
+    let mut state = state.lock().unwrap();
+    match candidates {
+        [] => {
+            if matches!(state.status, Status::Choosing(_)) {
+                state.status = Status::Waiting;
+            }
+            None
+        }
+        [candidate] => Some(candidate.clone()),
+        _ => {
+            let chosen = state
+                .choice
+                .take()
+                .and_then(|pid| candidates.iter().find(|candidate| candidate.pid == pid));
+            if chosen.is_none() {
+                if !matches!(state.status, Status::Choosing(_)) {
+                    info!(
+                        "Found {} game instances, waiting for a choice",
+                        candidates.len()
+                    );
+                    for candidate in candidates {
+                        info!(
+                            "{candidate}: {}",
+                            candidate.path.as_deref().unwrap_or("unknown path")
+                        );
+                    }
+                }
+                state.status = Status::Choosing(candidates.to_vec());
+            }
+            chosen.cloned()
+        }
+    }
+}
+
+fn requested_pid() -> Option<u32> {
+    let mut arguments = std::env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        let value = match argument.strip_prefix("--pid") {
+            Some("") => arguments.next(),
+            Some(value) if value.starts_with('=') => Some(value[1..].into()),
+            _ => continue,
+        };
+        match value.and_then(|value| value.parse().ok()) {
+            Some(pid) => return Some(pid),
+            None => warn!("Ignoring --pid without a valid process ID"),
+        }
+    }
+    None
+}
+
+fn check_version(game: &Game, state: &Mutex<State>) {
+    let notice = match game.check_version() {
+        Ok(()) => None,
+        Err(error) => {
+            warn!("{error}");
+            Some(error.to_string())
+        }
+    };
+    state.lock().unwrap().notice = notice;
+}
+
+// Tweaks whose pattern wasn't found are scanned for again, e.g. heap objects that don't exist until the game has loaded
+struct TweakLoader {
+    attempts: u32,
+    scan_eject_height: bool,
+    scan_sprint_speed: bool,
+}
+
+impl TweakLoader {
+    fn new() -> Self {
+        Self {
+            attempts: 0,
+            scan_eject_height: true,
+            scan_sprint_speed: true,
+        }
+    }
+
+    fn done(&self) -> bool {
+        !(self.scan_eject_height || self.scan_sprint_speed) || self.attempts == LOAD_ATTEMPTS
+    }
+
+    // Doubles after every attempt
+    fn delay(&self) -> Duration {
+        RESCAN_INTERVAL * 2u32.pow(self.attempts.saturating_sub(1))
+    }
+
+    fn load(&mut self, game: &Game, state: &Mutex<State>) {
+        let mut scanner = Scanner::new();
+        let eject_height = self
+            .scan_eject_height
+            .then(|| EjectHeightTweak::scan(game, &mut scanner));
+        let sprint_speed = self
+            .scan_sprint_speed
+            .then(|| SprintSpeedTweak::scan(game, &mut scanner));
+        let mut results = match game.scan_cache() {
+            Some(mut cache) => {
+                let results = scanner.scan_cached(game.process.as_ref(), &mut cache);
+                cache.save();
+                results
+            }
+            None => scanner.scan(game.process.as_ref()),
+        };
+
+        self.attempts += 1;
+        if let Some(id) = eject_height {
+            let tweak = EjectHeightTweak::new(game, results.take(id));
+            self.scan_eject_height = self.show(state, 0, tweak, EjectHeightTweak::load_config);
+        }
+        if let Some(id) = sprint_speed {
+            let tweak = SprintSpeedTweak::new(game, results.take_all(id));
+            self.scan_sprint_speed = self.show(state, 1, tweak, SprintSpeedTweak::load_config);
+        }
+        if !self.done() {
+            info!(
+                "Scanning for missing tweaks again in {} s",
+                self.delay().as_secs()
+            );
+        }
+    }
+
+    // Returns whether the tweak should be scanned for again
+    fn show<T: Tweak<f64> + 'static>(
+        &self,
+        state: &Mutex<State>,
+        index: usize,
+        mut tweak: Result<T>,
+        load_config: fn(&mut T),
+    ) -> bool {
+        let first = self.attempts == 1;
+        let retry = match &mut tweak {
+            Ok(tweak) => {
+                if !first {
+                    info!("Created {} tweak after {} attempts", T::NAME, self.attempts);
+                }
+                load_config(tweak);
+                false
+            }
+            Err(error) => {
+                let retry = error.is::<PatternNotFound>() && self.attempts < LOAD_ATTEMPTS;
+                if !retry {
+                    error!("Failed to create {} tweak: {error}", T::NAME);
+                } else if first {
+                    warn!("Couldn't create {} tweak yet ({error})", T::NAME);
+                }
+                retry
+            }
+        };
+        if retry && !first {
+            return true;
+        }
+
+        let control = Box::new(SliderControl::new(tweak));
+        let mut state = state.lock().unwrap();
+        if index < state.controls.len() {
+            state.controls[index] = control;
+        } else {
+            state.controls.push(control);
+        }
+        retry
+    }
+}
+
+#[cfg(windows)]
 fn hide_console() {
     unsafe {
         let mut processes = [0; 2];
@@ -88,6 +321,9 @@ fn hide_console() {
     }
 }
 
+#[cfg(not(windows))]
+fn hide_console() {}
+
 fn show_error(error: anyhow::Error) {
     rfd::MessageDialog::new()
         .set_level(rfd::MessageLevel::Error)
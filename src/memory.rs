@@ -0,0 +1,248 @@
+use crate::pe::PeImage;
+use anyhow::{anyhow, bail, Result};
+use std::mem::size_of;
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::Duration;
+
+const SUSPEND_ATTEMPTS: usize = 100;
+
+pub trait MemoryBackend: Send + Sync {
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<()>;
+    fn write_bytes(&self, address: usize, data: &[u8]) -> Result<()>;
+    fn regions(&self, start: usize) -> Vec<MemoryRegion>;
+    fn module_base(&self) -> usize;
+    fn modules(&self) -> Result<Vec<Module>>;
+    fn suspend_threads(&self) -> Result<Vec<usize>>;
+    fn resume_threads(&self);
+    fn allocate(&self, near: usize, size: usize) -> Result<usize>;
+    fn free_allocations(&self);
+    fn is_alive(&self) -> bool;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    pub address: usize,
+    pub size: usize,
+    pub readable: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub name: String,
+    pub base: usize,
+    pub size: usize,
+    pub path: String,
+}
+
+pub struct Suspension<'a> {
+    process: &'a dyn MemoryBackend,
+    pub instruction_pointers: Vec<usize>,
+}
+
+impl Drop for Suspension<'_> {
+    fn drop(&mut self) {
+        self.process.resume_threads();
+    }
+}
+
+#[cfg(test)]
+pub struct Region {
+    pub address: usize,
+    pub data: Vec<u8>,
+}
+
+impl dyn MemoryBackend + '_ {
+    pub fn main_image(&self) -> Result<PeImage> {
+        PeImage::read(self, self.module_base())
+    }
+
+    pub fn module(&self, name: &str) -> Result<Module> {
+        self.modules()?
+            .into_iter()
+            .find(|module| module.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("Couldn't find module {name}"))
+    }
+
+    pub fn suspend(&self) -> Result<Suspension<'_>> {
+        let instruction_pointers = self.suspend_threads()?;
+        Ok(Suspension {
+            process: self,
+            instruction_pointers,
+        })
+    }
+
+    pub fn write_code_bytes(&self, address: usize, data: &[u8]) -> Result<()> {
+        let inside = (address + 1)..(address + data.len());
+        for _ in 0..SUSPEND_ATTEMPTS {
+            let suspension = self.suspend()?;
+            if !suspension
+                .instruction_pointers
+                .iter()
+                .any(|pointer| inside.contains(pointer))
+            {
+                return self.write_bytes(address, data);
+            }
+
+            drop(suspension);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        bail!("Couldn't patch {address:#X}, a thread kept executing inside it");
+    }
+
+    pub fn read(&self, address: usize, size: usize) -> Result<Vec<u8>> {
+        let mut buffer = vec![0; size];
+        self.read_bytes(address, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub fn read_into<T>(&self, address: usize) -> Result<T> {
+        unsafe {
+            let mut value = std::mem::zeroed();
+            let buffer =
+                std::slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, size_of::<T>());
+            self.read_bytes(address, buffer)?;
+            Ok(value)
+        }
+    }
+}
+
+pub fn bytes_of<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+#[cfg(test)]
+pub struct BufferBackend {
+    base_address: usize,
+    regions: Mutex<Vec<Region>>,
+    modules: Vec<Module>,
+    allocations: Mutex<Vec<usize>>,
+}
+
+#[cfg(test)]
+impl BufferBackend {
+    pub fn new(base_address: usize) -> Self {
+        Self {
+            base_address,
+            regions: Mutex::new(Vec::new()),
+            modules: Vec::new(),
+            allocations: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_module(mut self, name: &str, base: usize, data: Vec<u8>) -> Self {
+        self.modules.push(Module {
+            name: name.into(),
+            base,
+            size: data.len(),
+            path: name.into(),
+        });
+        self.with_region(base, data)
+    }
+
+    pub fn with_region(self, address: usize, data: Vec<u8>) -> Self {
+        {
+            let mut regions = self.regions.lock().unwrap();
+            regions.push(Region { address, data });
+            regions.sort_by_key(|region| region.address);
+        }
+        self
+    }
+
+    fn locate(regions: &[Region], address: usize, size: usize) -> Result<(usize, usize)> {
+        regions
+            .iter()
+            .enumerate()
+            .find(|(_, region)| {
+                address >= region.address && address + size <= region.address + region.data.len()
+            })
+            .map(|(index, region)| (index, address - region.address))
+            .ok_or_else(|| anyhow!("No buffer covers {size} bytes at {address:#X}"))
+    }
+}
+
+#[cfg(test)]
+impl MemoryBackend for BufferBackend {
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<()> {
+        let regions = self.regions.lock().unwrap();
+        let (index, offset) = Self::locate(&regions, address, buffer.len())?;
+        buffer.copy_from_slice(&regions[index].data[offset..(offset + buffer.len())]);
+        Ok(())
+    }
+
+    fn write_bytes(&self, address: usize, data: &[u8]) -> Result<()> {
+        let mut regions = self.regions.lock().unwrap();
+        let (index, offset) = Self::locate(&regions, address, data.len())?;
+        regions[index].data[offset..(offset + data.len())].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn regions(&self, start: usize) -> Vec<MemoryRegion> {
+        let regions = self.regions.lock().unwrap();
+        let mut result = Vec::new();
+        let mut address = start;
+        for region in regions.iter() {
+            let end = region.address + region.data.len();
+            if end <= address {
+                continue;
+            }
+            if address < region.address {
+                result.push(MemoryRegion {
+                    address,
+                    size: region.address - address,
+                    readable: false,
+                });
+                address = region.address;
+            }
+            result.push(MemoryRegion {
+                address,
+                size: end - address,
+                readable: true,
+            });
+            address = end;
+        }
+        result
+    }
+
+    fn module_base(&self) -> usize {
+        self.base_address
+    }
+
+    fn modules(&self) -> Result<Vec<Module>> {
+        Ok(self.modules.clone())
+    }
+
+    fn suspend_threads(&self) -> Result<Vec<usize>> {
+        Ok(Vec::new())
+    }
+
+    fn resume_threads(&self) {}
+
+    fn allocate(&self, near: usize, size: usize) -> Result<usize> {
+        let mut regions = self.regions.lock().unwrap();
+        let end = regions
+            .iter()
+            .map(|region| region.address + region.data.len())
+            .max()
+            .unwrap_or(near);
+        let address = (end + 0xFFFF) & !0xFFFF;
+        regions.push(Region {
+            address,
+            data: vec![0; size],
+        });
+        self.allocations.lock().unwrap().push(address);
+        Ok(address)
+    }
+
+    fn free_allocations(&self) {
+        let allocations = std::mem::take(&mut *self.allocations.lock().unwrap());
+        self.regions
+            .lock()
+            .unwrap()
+            .retain(|region| !allocations.contains(&region.address));
+    }
+
+    fn is_alive(&self) -> bool {
+        true
+    }
+}
@@ -1,3 +1,4 @@
+use crate::process::Candidate;
 use crate::tweaks::{Tweak, TweakIntent};
 use anyhow::{anyhow, Result};
 use eframe::egui::{Button, Checkbox, Label, Slider};
@@ -5,15 +6,24 @@ use eframe::emath::Numeric;
 use eframe::{egui, IconData};
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const REPAINT_INTERVAL: Duration = Duration::from_millis(250);
 
 pub struct State {
     pub controls: Vec<Box<dyn Control>>,
     pub status: Status,
+    pub choice: Option<u32>,
+    pub notice: Option<String>,
+    pub error: Option<String>,
 }
 
 pub enum Status {
+    Waiting,
+    Choosing(Vec<Candidate>),
     Loading,
     Done,
+    Detached,
 }
 
 pub struct Menu {
@@ -53,6 +63,15 @@ impl Menu {
         context.egui_ctx.set_fonts(fonts);
         Box::new(self)
     }
+
+    fn show_controls(ui: &mut egui::Ui, controls: &mut [Box<dyn Control>]) {
+        egui::Grid::new("controls").show(ui, |ui| {
+            for control in controls {
+                control.show(ui);
+                ui.end_row();
+            }
+        });
+    }
 }
 
 impl eframe::App for Menu {
@@ -60,20 +79,54 @@ impl eframe::App for Menu {
         egui::CentralPanel::default().show(context, |ui| {
             let mut state = self.state.lock().unwrap();
             ui.heading("Mirage Tweaks");
-            match state.status {
+            if let (Status::Done | Status::Detached, Some(notice)) = (&state.status, &state.notice)
+            {
+                ui.colored_label(ui.visuals().warn_fg_color, notice);
+            }
+            match &state.status {
+                Status::Waiting => {
+                    ui.label("Waiting for Assassin's Creed Mirage...");
+                    if let Some(error) = &state.error {
+                        ui.colored_label(ui.visuals().error_fg_color, error);
+                    }
+                }
+                Status::Choosing(candidates) => {
+                    ui.label("Multiple instances of the game are running:");
+                    let mut choice = None;
+                    for candidate in candidates {
+                        let button = ui.button(candidate.to_string());
+                        if button
+                            .on_hover_text(candidate.path.as_deref().unwrap_or_default())
+                            .clicked()
+                        {
+                            choice = Some(candidate.pid);
+                        }
+                    }
+                    if choice.is_some() {
+                        state.choice = choice;
+                        state.status = Status::Loading;
+                    }
+                }
                 Status::Loading => {
                     ui.label("Loading Tweaks...");
                 }
                 Status::Done => {
-                    egui::Grid::new("controls").show(ui, |ui| {
-                        for control in &mut state.deref_mut().controls {
-                            control.show(ui);
-                            ui.end_row();
-                        }
+                    Self::show_controls(ui, &mut state.deref_mut().controls);
+                }
+                Status::Detached => {
+                    ui.label("Game closed, waiting for it to restart...");
+                    if let Some(error) = &state.error {
+                        ui.colored_label(ui.visuals().error_fg_color, error);
+                    }
+                    ui.add_enabled_ui(false, |ui| {
+                        Self::show_controls(ui, &mut state.deref_mut().controls);
                     });
                 }
             }
         });
+
+        // The loading thread changes the state without any input, e.g. when the game exits or a rescan finds a tweak
+        context.request_repaint_after(REPAINT_INTERVAL);
     }
 }
 
@@ -0,0 +1,111 @@
+use crate::memory::MemoryBackend;
+use anyhow::{bail, Result};
+
+pub const DOS_MAGIC: u16 = 0x5A4D;
+pub const NT_SIGNATURE: u32 = 0x4550;
+pub const PE32_PLUS_MAGIC: u16 = 0x20B;
+pub const SECTION_HEADER_SIZE: usize = 40;
+
+pub struct PeImage {
+    pub base: usize,
+    pub timestamp: u32,
+    pub image_size: u32,
+    pub sections: Vec<PeSection>,
+}
+
+pub struct PeSection {
+    pub name: String,
+    pub address: usize,
+    pub size: usize,
+}
+
+impl PeImage {
+    pub fn read(process: &dyn MemoryBackend, base: usize) -> Result<Self> {
+        if process.read_into::<u16>(base)? != DOS_MAGIC {
+            bail!("No DOS header at {base:#X}");
+        }
+
+        let nt_headers = base + process.read_into::<u32>(base + 0x3C)? as usize;
+        if process.read_into::<u32>(nt_headers)? != NT_SIGNATURE {
+            bail!("No NT headers at {nt_headers:#X}");
+        }
+
+        // IMAGE_FILE_HEADER followed by IMAGE_OPTIONAL_HEADER64
+        let file_header = process.read(nt_headers + 4, 20)?;
+        let section_count = u16::from_le_bytes([file_header[2], file_header[3]]) as usize;
+        let timestamp = u32::from_le_bytes(file_header[4..8].try_into().unwrap());
+        let optional_header_size = u16::from_le_bytes([file_header[16], file_header[17]]) as usize;
+
+        let optional_header = nt_headers + 24;
+        if process.read_into::<u16>(optional_header)? != PE32_PLUS_MAGIC {
+            bail!("Image at {base:#X} isn't a PE32+ image");
+        }
+        let image_size = process.read_into::<u32>(optional_header + 56)?;
+
+        let section_headers = process.read(
+            optional_header + optional_header_size,
+            section_count * SECTION_HEADER_SIZE,
+        )?;
+        let sections = section_headers
+            .chunks_exact(SECTION_HEADER_SIZE)
+            .map(|header| {
+                let name = header[..8]
+                    .split(|&byte| byte == 0)
+                    .next()
+                    .unwrap_or_default();
+                let virtual_size = u32::from_le_bytes(header[8..12].try_into().unwrap());
+                let virtual_address = u32::from_le_bytes(header[12..16].try_into().unwrap());
+                let raw_size = u32::from_le_bytes(header[16..20].try_into().unwrap());
+                PeSection {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    address: base + virtual_address as usize,
+                    size: match virtual_size {
+                        0 => raw_size as usize,
+                        _ => virtual_size as usize,
+                    },
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            base,
+            timestamp,
+            image_size,
+            sections,
+        })
+    }
+
+    pub fn section(&self, name: &str) -> Result<&PeSection> {
+        match self.sections.iter().find(|section| section.name == name) {
+            Some(section) => Ok(section),
+            None => bail!("Image at {:#X} has no {name} section", self.base),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BufferBackend;
+    use crate::test_util::{backend, BASE};
+
+    #[test]
+    fn reads_headers_and_sections() {
+        let process = backend(&[(".text", &[0xCC; 0x20]), (".rdata", &[0; 0x1800])]);
+        let image = PeImage::read(&process, BASE).unwrap();
+
+        assert_eq!(image.timestamp, 0x6512_3456);
+        assert_eq!(image.image_size, 0x4000);
+        let text = image.section(".text").unwrap();
+        assert_eq!((text.address, text.size), (BASE + 0x1000, 0x20));
+        let rdata = image.section(".rdata").unwrap();
+        assert_eq!((rdata.address, rdata.size), (BASE + 0x2000, 0x1800));
+        assert!(image.section(".data").is_err());
+    }
+
+    #[test]
+    fn rejects_missing_headers() {
+        let process = BufferBackend::new(BASE).with_region(BASE, vec![0; 0x1000]);
+        assert!(PeImage::read(&process, BASE).is_err());
+    }
+}
@@ -0,0 +1,438 @@
+use super::{module_names, Candidate};
+use crate::cave::allocation_candidates;
+use crate::memory::{MemoryBackend, MemoryRegion, Module};
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TASK_COMM_LEN: usize = 15;
+const STOP_ATTEMPTS: usize = 100;
+const PAGE_SIZE: usize = 0x1000;
+const SYSCALL: [u8; 2] = [0x0F, 0x05];
+
+pub struct Process {
+    pid: u32,
+    started: Option<SystemTime>,
+    memory: File,
+    base_address: usize,
+    allocations: Mutex<Vec<(usize, usize)>>,
+}
+
+impl Process {
+    pub fn candidates() -> Result<Vec<Candidate>> {
+        let module_names = module_names();
+        let mut candidates = Vec::new();
+
+        for entry in std::fs::read_dir("/proc")? {
+            let Some(pid) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            else {
+                continue;
+            };
+            let Some(name) = matching_name(pid, &module_names) else {
+                continue;
+            };
+            candidates.push(Candidate {
+                pid,
+                name: name.into(),
+                path: program_path(pid),
+                started: start_time(pid),
+            });
+        }
+
+        Ok(candidates)
+    }
+
+    pub fn open(candidate: &Candidate) -> Result<Self> {
+        let pid = candidate.pid;
+        let name = &candidate.name;
+        info!("Attaching to process {pid} ({name})");
+
+        let memory = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{pid}/mem"))
+            .with_context(|| {
+                format!("Couldn't open memory of process {pid}, is ptrace access allowed?")
+            })?;
+
+        let base_address = mappings(pid)?
+            .iter()
+            .filter(|mapping| file_name(&mapping.path).eq_ignore_ascii_case(name))
+            .map(|mapping| mapping.start)
+            .min()
+            .ok_or_else(|| anyhow!("Couldn't find the image mapping of {name}"))?;
+
+        Ok(Self {
+            pid,
+            started: candidate.started,
+            memory,
+            base_address,
+            allocations: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl Process {
+    // Runs a system call in the main thread of the game by pointing it at a syscall instruction
+    fn syscall(&self, number: libc::c_long, arguments: [u64; 6]) -> Result<u64> {
+        let instruction = mappings(self.pid)?
+            .iter()
+            .filter(|mapping| mapping.executable && mapping.path == "[vdso]")
+            .find_map(|mapping| {
+                let mut code = vec![0; mapping.end - mapping.start];
+                self.read_bytes(mapping.start, &mut code).ok()?;
+                let offset = code.windows(2).position(|pair| pair == SYSCALL)?;
+                Some(mapping.start + offset)
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "Couldn't find a syscall instruction in process {}",
+                    self.pid
+                )
+            })?;
+
+        let pid = self.pid as libc::pid_t;
+        unsafe {
+            if libc::ptrace(libc::PTRACE_ATTACH, pid, 0, 0) != 0 {
+                bail!(
+                    "Couldn't trace process {pid} ({})",
+                    io::Error::last_os_error()
+                );
+            }
+            let result = Self::traced_syscall(pid, instruction, number, arguments);
+            libc::ptrace(libc::PTRACE_DETACH, pid, 0, 0);
+            result
+        }
+    }
+
+    unsafe fn traced_syscall(
+        pid: libc::pid_t,
+        instruction: usize,
+        number: libc::c_long,
+        arguments: [u64; 6],
+    ) -> Result<u64> {
+        let mut status = 0;
+        if libc::waitpid(pid, &mut status, libc::__WALL) != pid {
+            bail!("Couldn't wait for process {pid} to stop");
+        }
+
+        let mut saved: libc::user_regs_struct = std::mem::zeroed();
+        if libc::ptrace(libc::PTRACE_GETREGS, pid, 0, &mut saved) != 0 {
+            bail!("Couldn't read registers of process {pid}");
+        }
+
+        let mut registers = saved;
+        registers.rax = number as u64;
+        registers.orig_rax = u64::MAX;
+        registers.rdi = arguments[0];
+        registers.rsi = arguments[1];
+        registers.rdx = arguments[2];
+        registers.r10 = arguments[3];
+        registers.r8 = arguments[4];
+        registers.r9 = arguments[5];
+        registers.rip = instruction as u64;
+
+        let mut result = None;
+        if libc::ptrace(libc::PTRACE_SETREGS, pid, 0, &registers) == 0
+            && libc::ptrace(libc::PTRACE_SINGLESTEP, pid, 0, 0) == 0
+            && libc::waitpid(pid, &mut status, libc::__WALL) == pid
+            && libc::ptrace(libc::PTRACE_GETREGS, pid, 0, &mut registers) == 0
+        {
+            result = Some(registers.rax);
+        }
+
+        if libc::ptrace(libc::PTRACE_SETREGS, pid, 0, &saved) != 0 {
+            warn!("Couldn't restore registers of process {pid}");
+        }
+
+        match result {
+            // Values from -4095 to -1 are errno codes
+            Some(value) if value > -4096i64 as u64 => bail!(
+                "System call {number} failed ({})",
+                io::Error::from_raw_os_error(-(value as i64) as i32)
+            ),
+            Some(value) => Ok(value),
+            None => bail!("Couldn't run system call {number} in process {pid}"),
+        }
+    }
+
+    fn instruction_pointers(&self) -> Result<Vec<usize>> {
+        let mut instruction_pointers = Vec::new();
+
+        for entry in std::fs::read_dir(format!("/proc/{}/task", self.pid))? {
+            let task = entry?.path();
+
+            // Wait for the thread to actually stop, only then does syscall report its registers
+            let mut attempts = 0;
+            loop {
+                let stat = std::fs::read_to_string(task.join("stat"))?;
+                let state = stat.rsplit(") ").next().unwrap_or_default();
+                if state.starts_with(['T', 't']) {
+                    break;
+                }
+                attempts += 1;
+                if attempts == STOP_ATTEMPTS {
+                    bail!("Thread {} didn't stop", task.display());
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+
+            let syscall = std::fs::read_to_string(task.join("syscall"))?;
+            let pointer = syscall.split_whitespace().last().unwrap_or_default();
+            let pointer = usize::from_str_radix(pointer.trim_start_matches("0x"), 16)
+                .with_context(|| format!("Couldn't parse {syscall:?} of {}", task.display()))?;
+            instruction_pointers.push(pointer);
+        }
+
+        Ok(instruction_pointers)
+    }
+}
+
+impl MemoryBackend for Process {
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<()> {
+        self.memory
+            .read_exact_at(buffer, address as u64)
+            .with_context(|| format!("Couldn't read {} bytes at {address:#X}", buffer.len()))
+    }
+
+    fn write_bytes(&self, address: usize, data: &[u8]) -> Result<()> {
+        self.memory
+            .write_all_at(data, address as u64)
+            .with_context(|| format!("Couldn't write {} bytes at {address:#X}", data.len()))
+    }
+
+    fn regions(&self, start: usize) -> Vec<MemoryRegion> {
+        mappings(self.pid)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|mapping| mapping.end > start)
+            .map(|mapping| MemoryRegion {
+                address: mapping.start.max(start),
+                size: mapping.end - mapping.start.max(start),
+                readable: mapping.readable
+                    && mapping.path != "[vvar]"
+                    && mapping.path != "[vsyscall]",
+            })
+            .collect()
+    }
+
+    fn module_base(&self) -> usize {
+        self.base_address
+    }
+
+    fn modules(&self) -> Result<Vec<Module>> {
+        let mut modules: Vec<Module> = Vec::new();
+
+        for mapping in mappings(self.pid)? {
+            let name = file_name(&mapping.path);
+            let extension = name.rsplit('.').next().unwrap_or_default();
+            if !extension.eq_ignore_ascii_case("exe") && !extension.eq_ignore_ascii_case("dll") {
+                continue;
+            }
+
+            match modules
+                .iter_mut()
+                .find(|module| module.path == mapping.path)
+            {
+                Some(module) => {
+                    let end = (module.base + module.size).max(mapping.end);
+                    module.base = module.base.min(mapping.start);
+                    module.size = end - module.base;
+                }
+                None => modules.push(Module {
+                    name: name.into(),
+                    base: mapping.start,
+                    size: mapping.end - mapping.start,
+                    path: mapping.path.clone(),
+                }),
+            }
+        }
+
+        Ok(modules)
+    }
+
+    fn suspend_threads(&self) -> Result<Vec<usize>> {
+        unsafe {
+            if libc::kill(self.pid as libc::pid_t, libc::SIGSTOP) != 0 {
+                bail!(
+                    "Couldn't stop process {} ({})",
+                    self.pid,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+
+        match self.instruction_pointers() {
+            Ok(instruction_pointers) => Ok(instruction_pointers),
+            Err(error) => {
+                self.resume_threads();
+                Err(error)
+            }
+        }
+    }
+
+    fn resume_threads(&self) {
+        unsafe {
+            libc::kill(self.pid as libc::pid_t, libc::SIGCONT);
+        }
+    }
+
+    fn allocate(&self, near: usize, size: usize) -> Result<usize> {
+        let mappings = mappings(self.pid)?;
+        let free = mappings
+            .windows(2)
+            .map(|pair| (pair[0].end, pair[1].start))
+            .collect::<Vec<_>>();
+
+        for candidate in allocation_candidates(&free, near, size, PAGE_SIZE) {
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+            let result = self.syscall(
+                libc::SYS_mmap,
+                [
+                    candidate as u64,
+                    size as u64,
+                    (libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC) as u64,
+                    flags as u64,
+                    u64::MAX,
+                    0,
+                ],
+            );
+
+            match result {
+                Ok(address) if address as usize == candidate => {
+                    self.allocations.lock().unwrap().push((candidate, size));
+                    return Ok(candidate);
+                }
+                // Kernels without MAP_FIXED_NOREPLACE treat the address as a hint
+                Ok(address) => {
+                    self.syscall(libc::SYS_munmap, [address, size as u64, 0, 0, 0, 0])?;
+                }
+                Err(error) => warn!("Couldn't map {size} bytes at {candidate:#X} ({error})"),
+            }
+        }
+
+        bail!("Couldn't allocate {size} bytes near {near:#X}");
+    }
+
+    fn free_allocations(&self) {
+        let allocations = std::mem::take(&mut *self.allocations.lock().unwrap());
+        if !self.is_alive() {
+            return;
+        }
+        for (address, size) in allocations {
+            info!("Freeing allocation at {address:#X}");
+            let arguments = [address as u64, size as u64, 0, 0, 0, 0];
+            if let Err(error) = self.syscall(libc::SYS_munmap, arguments) {
+                warn!("Couldn't free allocation at {address:#X} ({error})");
+            }
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", self.pid)) {
+            Ok(stat) => {
+                // A different process may have been given the same PID since the game exited
+                let state = stat.rsplit(") ").next().unwrap_or_default();
+                !state.starts_with(['Z', 'X']) && start_time(self.pid) == self.started
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.free_allocations();
+    }
+}
+
+struct Mapping {
+    start: usize,
+    end: usize,
+    readable: bool,
+    executable: bool,
+    path: String,
+}
+
+fn mappings(pid: u32) -> Result<Vec<Mapping>> {
+    let maps = std::fs::read_to_string(format!("/proc/{pid}/maps"))?;
+    let mut mappings = Vec::new();
+
+    for line in maps.lines() {
+        // start-end perms offset dev inode [path]
+        let mut fields = line.splitn(6, ' ');
+        let range = fields.next().unwrap_or_default();
+        let permissions = fields.next().unwrap_or_default();
+        let path = fields.nth(3).unwrap_or_default().trim_start();
+
+        let Some((start, end)) = range.split_once('-') else {
+            continue;
+        };
+        mappings.push(Mapping {
+            start: usize::from_str_radix(start, 16)?,
+            end: usize::from_str_radix(end, 16)?,
+            readable: permissions.starts_with('r'),
+            executable: permissions.get(2..3) == Some("x"),
+            path: path.into(),
+        });
+    }
+
+    Ok(mappings)
+}
+
+fn matching_name(pid: u32, module_names: &[String]) -> Option<&str> {
+    let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    let comm = comm.trim_end();
+    let cmdline = std::fs::read(format!("/proc/{pid}/cmdline")).unwrap_or_default();
+    let program = cmdline.split(|&byte| byte == 0).next().unwrap_or_default();
+    let program = String::from_utf8_lossy(program);
+
+    module_names
+        .iter()
+        .find(|name| {
+            file_name(&program).eq_ignore_ascii_case(name)
+                || name.get(..TASK_COMM_LEN).unwrap_or(name) == comm
+        })
+        .map(String::as_str)
+}
+
+// Under Wine the executable is the preloader, which replaces argv[0] with the Windows path of the game
+fn program_path(pid: u32) -> Option<String> {
+    let cmdline = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let program = cmdline.split(|&byte| byte == 0).next().unwrap_or_default();
+    if !program.is_empty() {
+        return Some(String::from_utf8_lossy(program).into());
+    }
+    let executable = std::fs::read_link(format!("/proc/{pid}/exe")).ok()?;
+    Some(executable.to_string_lossy().into())
+}
+
+fn start_time(pid: u32) -> Option<SystemTime> {
+    // Field 22 of stat, in clock ticks since boot
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let ticks: u64 = stat.rsplit(") ").next()?.split(' ').nth(19)?.parse().ok()?;
+    let boot: u64 = std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .parse()
+        .ok()?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    let started =
+        Duration::from_secs(boot) + Duration::from_secs_f64(ticks as f64 / ticks_per_second as f64);
+    Some(UNIX_EPOCH + started)
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
@@ -0,0 +1,54 @@
+use crate::config::CONFIG;
+use std::fmt::{self, Display, Formatter};
+use std::time::SystemTime;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(target_os = "linux")]
+pub use self::linux::Process;
+#[cfg(windows)]
+pub use self::windows::Process;
+
+fn module_names() -> Vec<String> {
+    CONFIG
+        .lock()
+        .unwrap()
+        .module_names
+        .clone()
+        .unwrap_or(vec!["ACMirage.exe".into(), "ACMirage_plus.exe".into()])
+}
+
+// A running process that matches one of the module names
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub pid: u32,
+    pub name: String,
+    pub path: Option<String>,
+    pub started: Option<SystemTime>,
+}
+
+impl Display for Candidate {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "{} ({})", self.name, self.pid)?;
+        let running = self
+            .started
+            .and_then(|started| SystemTime::now().duration_since(started).ok());
+        if let Some(running) = running {
+            let minutes = running.as_secs() / 60;
+            match minutes {
+                0 => write!(formatter, ", started just now")?,
+                1..=59 => write!(formatter, ", started {minutes} min ago")?,
+                _ => write!(
+                    formatter,
+                    ", started {} h {} min ago",
+                    minutes / 60,
+                    minutes % 60
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
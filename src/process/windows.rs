@@ -0,0 +1,504 @@
+use super::{module_names, Candidate};
+use crate::cave::{allocation_candidates, REL32_RANGE};
+use crate::memory::{MemoryBackend, MemoryRegion, Module};
+use anyhow::{bail, Result};
+use log::{info, warn};
+use std::ffi::CStr;
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use windows::core::PWSTR;
+use windows::Win32::Foundation::{CloseHandle, FILETIME, HANDLE, MAX_PATH, STILL_ACTIVE};
+use windows::Win32::System::Diagnostics::Debug::{
+    FlushInstructionCache, GetThreadContext, ReadProcessMemory, WriteProcessMemory, CONTEXT,
+    CONTEXT_CONTROL_AMD64,
+};
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Module32First, Module32Next, Process32First, Process32Next,
+    Thread32First, Thread32Next, CREATE_TOOLHELP_SNAPSHOT_FLAGS, MODULEENTRY32, PROCESSENTRY32,
+    TH32CS_SNAPMODULE, TH32CS_SNAPPROCESS, TH32CS_SNAPTHREAD, THREADENTRY32,
+};
+use windows::Win32::System::Memory::{
+    VirtualAllocEx, VirtualFreeEx, VirtualProtectEx, VirtualQueryEx, MEMORY_BASIC_INFORMATION,
+    MEM_COMMIT, MEM_FREE, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE, PAGE_EXECUTE_READ,
+    PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY, PAGE_GUARD, PAGE_NOACCESS,
+    PAGE_PROTECTION_FLAGS, PAGE_READWRITE, PAGE_WRITECOPY,
+};
+use windows::Win32::System::Threading::{
+    GetExitCodeProcess, GetProcessTimes, OpenProcess, OpenThread, QueryFullProcessImageNameW,
+    ResumeThread, SuspendThread, PROCESS_ACCESS_RIGHTS, PROCESS_NAME_WIN32,
+    PROCESS_QUERY_INFORMATION, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_VM_OPERATION,
+    PROCESS_VM_READ, PROCESS_VM_WRITE, THREAD_GET_CONTEXT, THREAD_SUSPEND_RESUME,
+};
+
+pub struct Process {
+    handle: HANDLE,
+    pid: u32,
+    base_address: usize,
+    suspended: Mutex<Vec<HANDLE>>,
+    allocations: Mutex<Vec<usize>>,
+}
+
+const ALLOCATION_GRANULARITY: usize = 0x10000;
+
+#[repr(C, align(16))]
+struct AlignedContext(CONTEXT);
+
+impl Process {
+    pub fn candidates() -> Result<Vec<Candidate>> {
+        let module_names = module_names();
+        let mut candidates = Vec::new();
+
+        unsafe {
+            let snapshot = Snapshot::new(TH32CS_SNAPPROCESS, 0)?;
+
+            let mut process = PROCESSENTRY32 {
+                dwSize: size_of::<PROCESSENTRY32>() as u32,
+                ..Default::default()
+            };
+
+            Process32First(snapshot.handle, &mut process)?;
+
+            loop {
+                if let Ok(name) = CStr::from_ptr(process.szExeFile.as_ptr() as _).to_str() {
+                    if module_names.contains(&name.into()) {
+                        let pid = process.th32ProcessID;
+                        let (path, started) = match details(pid) {
+                            Ok(details) => details,
+                            Err(error) => {
+                                warn!("Couldn't query process {pid} ({error})");
+                                (None, None)
+                            }
+                        };
+                        candidates.push(Candidate {
+                            pid,
+                            name: name.into(),
+                            path,
+                            started,
+                        });
+                    }
+                }
+
+                if Process32Next(snapshot.handle, &mut process).is_err() {
+                    break;
+                }
+            }
+        }
+
+        Ok(candidates)
+    }
+
+    pub fn open(candidate: &Candidate) -> Result<Self> {
+        let pid = candidate.pid;
+        info!("Attaching to process {pid} ({})", candidate.name);
+
+        unsafe {
+            let rights = PROCESS_ACCESS_RIGHTS::default()
+                | PROCESS_QUERY_INFORMATION
+                | PROCESS_VM_OPERATION
+                | PROCESS_VM_READ
+                | PROCESS_VM_WRITE;
+            let handle = OpenProcess(rights, false, pid)?;
+
+            let base_address = match main_module_base(pid) {
+                Ok(base_address) => base_address,
+                Err(error) => {
+                    let _ = CloseHandle(handle);
+                    return Err(error);
+                }
+            };
+
+            Ok(Self {
+                handle,
+                pid,
+                base_address,
+                suspended: Mutex::new(Vec::new()),
+                allocations: Mutex::new(Vec::new()),
+            })
+        }
+    }
+}
+
+impl Process {
+    fn query(&self, address: usize) -> Result<MEMORY_BASIC_INFORMATION> {
+        let mut information = MEMORY_BASIC_INFORMATION::default();
+        unsafe {
+            if VirtualQueryEx(
+                self.handle,
+                Some(address as _),
+                &mut information,
+                size_of::<MEMORY_BASIC_INFORMATION>(),
+            ) == 0
+            {
+                bail!("Couldn't query memory at {address:#X}");
+            }
+        }
+        Ok(information)
+    }
+
+    fn write_region(
+        &self,
+        address: usize,
+        data: &[u8],
+        protection: PAGE_PROTECTION_FLAGS,
+    ) -> Result<()> {
+        // The low byte holds the access protection, the rest are modifiers like PAGE_GUARD
+        let access = PAGE_PROTECTION_FLAGS(protection.0 & 0xFF);
+        let writable = [
+            PAGE_READWRITE,
+            PAGE_WRITECOPY,
+            PAGE_EXECUTE_READWRITE,
+            PAGE_EXECUTE_WRITECOPY,
+        ]
+        .contains(&access);
+        let executable = [
+            PAGE_EXECUTE,
+            PAGE_EXECUTE_READ,
+            PAGE_EXECUTE_READWRITE,
+            PAGE_EXECUTE_WRITECOPY,
+        ]
+        .contains(&access);
+
+        unsafe {
+            let mut original = PAGE_PROTECTION_FLAGS::default();
+            if !writable {
+                let protection = if executable {
+                    PAGE_EXECUTE_READWRITE
+                } else {
+                    PAGE_READWRITE
+                };
+                VirtualProtectEx(
+                    self.handle,
+                    address as _,
+                    data.len(),
+                    protection,
+                    &mut original,
+                )?;
+            }
+
+            let mut written = 0;
+            let result = WriteProcessMemory(
+                self.handle,
+                address as _,
+                data.as_ptr() as _,
+                data.len(),
+                Some(&mut written),
+            );
+
+            if !writable {
+                if let Err(error) = VirtualProtectEx(
+                    self.handle,
+                    address as _,
+                    data.len(),
+                    original,
+                    &mut original,
+                ) {
+                    warn!("Couldn't restore protection of {address:#X} ({error})");
+                }
+            }
+            let flushed = if executable {
+                FlushInstructionCache(self.handle, Some(address as _), data.len())
+            } else {
+                Ok(())
+            };
+
+            result?;
+            if written != data.len() {
+                bail!(
+                    "Only wrote {written} of {} bytes at {address:#X}",
+                    data.len()
+                );
+            }
+            if let Err(error) = flushed {
+                bail!("Couldn't flush instruction cache at {address:#X} ({error})");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl MemoryBackend for Process {
+    fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<()> {
+        let mut read = 0;
+        unsafe {
+            ReadProcessMemory(
+                self.handle,
+                address as _,
+                buffer.as_mut_ptr() as _,
+                buffer.len(),
+                Some(&mut read),
+            )?;
+        }
+        if read != buffer.len() {
+            bail!("Only read {read} of {} bytes at {address:#X}", buffer.len());
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&self, address: usize, data: &[u8]) -> Result<()> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let address = address + offset;
+            let information = self.query(address)?;
+            let region_end = information.BaseAddress as usize + information.RegionSize;
+            let size = (data.len() - offset).min(region_end - address);
+            self.write_region(address, &data[offset..(offset + size)], information.Protect)?;
+            offset += size;
+        }
+        Ok(())
+    }
+
+    fn regions(&self, start: usize) -> Vec<MemoryRegion> {
+        let mut regions = Vec::new();
+        let mut address = start;
+
+        unsafe {
+            let mut information = MEMORY_BASIC_INFORMATION::default();
+            while VirtualQueryEx(
+                self.handle,
+                Some(address as _),
+                &mut information,
+                size_of::<MEMORY_BASIC_INFORMATION>(),
+            ) != 0
+            {
+                regions.push(MemoryRegion {
+                    address: information.BaseAddress as usize,
+                    size: information.RegionSize,
+                    readable: information.State == MEM_COMMIT
+                        && !information.Protect.contains(PAGE_NOACCESS)
+                        && !information.Protect.contains(PAGE_GUARD),
+                });
+                address = information.BaseAddress as usize + information.RegionSize;
+            }
+        }
+
+        regions
+    }
+
+    fn module_base(&self) -> usize {
+        self.base_address
+    }
+
+    fn modules(&self) -> Result<Vec<Module>> {
+        let mut modules = Vec::new();
+
+        unsafe {
+            let snapshot = Snapshot::new(TH32CS_SNAPMODULE, self.pid)?;
+            let mut module = MODULEENTRY32 {
+                dwSize: size_of::<MODULEENTRY32>() as u32,
+                ..Default::default()
+            };
+
+            Module32First(snapshot.handle, &mut module)?;
+            loop {
+                let name = CStr::from_ptr(module.szModule.as_ptr() as _);
+                let path = CStr::from_ptr(module.szExePath.as_ptr() as _);
+                modules.push(Module {
+                    name: name.to_string_lossy().into_owned(),
+                    base: module.modBaseAddr as usize,
+                    size: module.modBaseSize as usize,
+                    path: path.to_string_lossy().into_owned(),
+                });
+
+                if Module32Next(snapshot.handle, &mut module).is_err() {
+                    break;
+                }
+            }
+        }
+
+        Ok(modules)
+    }
+
+    fn suspend_threads(&self) -> Result<Vec<usize>> {
+        let mut suspended = self.suspended.lock().unwrap();
+        let mut instruction_pointers = Vec::new();
+
+        unsafe {
+            let snapshot = Snapshot::new(TH32CS_SNAPTHREAD, 0)?;
+            let mut thread = THREADENTRY32 {
+                dwSize: size_of::<THREADENTRY32>() as u32,
+                ..Default::default()
+            };
+
+            Thread32First(snapshot.handle, &mut thread)?;
+            loop {
+                if thread.th32OwnerProcessID == self.pid {
+                    let rights = THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT;
+                    // Threads may exit between the snapshot and opening them
+                    if let Ok(handle) = OpenThread(rights, false, thread.th32ThreadID) {
+                        if SuspendThread(handle) == u32::MAX {
+                            let _ = CloseHandle(handle);
+                        } else {
+                            suspended.push(handle);
+
+                            let mut context = AlignedContext(CONTEXT {
+                                ContextFlags: CONTEXT_CONTROL_AMD64,
+                                ..Default::default()
+                            });
+                            if let Err(error) = GetThreadContext(handle, &mut context.0) {
+                                resume(&mut suspended);
+                                bail!(
+                                    "Couldn't get context of thread {} ({error})",
+                                    thread.th32ThreadID
+                                );
+                            }
+                            instruction_pointers.push(context.0.Rip as usize);
+                        }
+                    }
+                }
+
+                if Thread32Next(snapshot.handle, &mut thread).is_err() {
+                    break;
+                }
+            }
+        }
+
+        Ok(instruction_pointers)
+    }
+
+    fn resume_threads(&self) {
+        resume(&mut self.suspended.lock().unwrap());
+    }
+
+    fn allocate(&self, near: usize, size: usize) -> Result<usize> {
+        let mut free = Vec::new();
+        let mut address = near.saturating_sub(REL32_RANGE);
+        while address < near.saturating_add(REL32_RANGE) {
+            let Ok(information) = self.query(address) else {
+                break;
+            };
+            let start = information.BaseAddress as usize;
+            address = start + information.RegionSize;
+            if information.State == MEM_FREE {
+                free.push((start, address));
+            }
+        }
+
+        for candidate in allocation_candidates(&free, near, size, ALLOCATION_GRANULARITY) {
+            unsafe {
+                let address = VirtualAllocEx(
+                    self.handle,
+                    Some(candidate as _),
+                    size,
+                    MEM_COMMIT | MEM_RESERVE,
+                    PAGE_EXECUTE_READWRITE,
+                );
+                if !address.is_null() {
+                    self.allocations.lock().unwrap().push(address as usize);
+                    return Ok(address as usize);
+                }
+            }
+        }
+
+        bail!("Couldn't allocate {size} bytes near {near:#X}");
+    }
+
+    fn free_allocations(&self) {
+        let allocations = std::mem::take(&mut *self.allocations.lock().unwrap());
+        // The allocations went away with the game if it exited
+        if !self.is_alive() {
+            return;
+        }
+        for address in allocations {
+            info!("Freeing allocation at {address:#X}");
+            if let Err(error) = unsafe { VirtualFreeEx(self.handle, address as _, 0, MEM_RELEASE) }
+            {
+                warn!("Couldn't free allocation at {address:#X} ({error})");
+            }
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        let mut code = 0;
+        unsafe {
+            GetExitCodeProcess(self.handle, &mut code).is_ok() && code == STILL_ACTIVE.0 as u32
+        }
+    }
+}
+
+fn resume(suspended: &mut Vec<HANDLE>) {
+    for handle in suspended.drain(..) {
+        unsafe {
+            ResumeThread(handle);
+            let _ = CloseHandle(handle);
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        self.resume_threads();
+        self.free_allocations();
+        unsafe {
+            let _ = CloseHandle(self.handle);
+        }
+    }
+}
+
+fn main_module_base(pid: u32) -> Result<usize> {
+    unsafe {
+        let snapshot = Snapshot::new(TH32CS_SNAPMODULE, pid)?;
+        let mut module = MODULEENTRY32 {
+            dwSize: size_of::<MODULEENTRY32>() as u32,
+            ..Default::default()
+        };
+        Module32First(snapshot.handle, &mut module)?;
+        Ok(module.modBaseAddr as usize)
+    }
+}
+
+// Image path and creation time of a process, without opening it for memory access
+fn details(pid: u32) -> Result<(Option<String>, Option<SystemTime>)> {
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)?;
+
+        let mut path = [0; MAX_PATH as usize];
+        let mut size = path.len() as u32;
+        let path = QueryFullProcessImageNameW(
+            handle,
+            PROCESS_NAME_WIN32,
+            PWSTR(path.as_mut_ptr()),
+            &mut size,
+        )
+        .ok()
+        .map(|_| String::from_utf16_lossy(&path[..size as usize]));
+
+        let mut times = [FILETIME::default(); 4];
+        let [creation, exit, kernel, user] = &mut times;
+        let started = GetProcessTimes(handle, creation, exit, kernel, user)
+            .ok()
+            .and_then(|_| {
+                // 100 nanosecond intervals since 1601
+                let intervals =
+                    (creation.dwHighDateTime as u64) << 32 | creation.dwLowDateTime as u64;
+                let unix = intervals.checked_sub(116_444_736_000_000_000)?;
+                Some(UNIX_EPOCH + Duration::from_nanos(unix * 100))
+            });
+
+        let _ = CloseHandle(handle);
+        Ok((path, started))
+    }
+}
+
+struct Snapshot {
+    handle: HANDLE,
+}
+
+impl Snapshot {
+    fn new(flags: CREATE_TOOLHELP_SNAPSHOT_FLAGS, pid: u32) -> Result<Self> {
+        unsafe {
+            Ok(Self {
+                handle: CreateToolhelp32Snapshot(flags, pid)?,
+            })
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.handle);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::memory::MemoryBackend;
+use anyhow::{bail, Result};
+
+#[derive(Debug, Clone)]
+pub struct PatchRecord {
+    pub id: usize,
+    pub owner: &'static str,
+    pub address: usize,
+    pub original: Vec<u8>,
+    pub current: Vec<u8>,
+    pub code: bool,
+}
+
+#[derive(Default)]
+pub struct PatchRegistry {
+    next_id: usize,
+    records: Vec<PatchRecord>,
+}
+
+impl PatchRegistry {
+    pub fn check(&self, owner: &str, address: usize, size: usize) -> Result<()> {
+        let overlapping = self.records.iter().find(|record| {
+            record.owner != owner
+                && address < record.address + record.original.len()
+                && record.address < address + size
+        });
+        if let Some(record) = overlapping {
+            bail!(
+                "Patch at {address:#X} overlaps the patch at {:#X} owned by {}",
+                record.address,
+                record.owner
+            );
+        }
+        Ok(())
+    }
+
+    pub fn register(
+        &mut self,
+        owner: &'static str,
+        address: usize,
+        original: Vec<u8>,
+        current: Vec<u8>,
+        code: bool,
+    ) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.records.push(PatchRecord {
+            id,
+            owner,
+            address,
+            original,
+            current,
+            code,
+        });
+        id
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut PatchRecord> {
+        self.records.iter_mut().find(|record| record.id == id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<PatchRecord> {
+        let index = self.records.iter().position(|record| record.id == id)?;
+        Some(self.records.remove(index))
+    }
+
+    #[cfg(test)]
+    pub fn records(&self) -> &[PatchRecord] {
+        &self.records
+    }
+
+    pub fn take_all(&mut self) -> Vec<PatchRecord> {
+        std::mem::take(&mut self.records)
+    }
+}
+
+impl PatchRecord {
+    pub fn verify(&self, process: &dyn MemoryBackend) -> Result<()> {
+        let live = process.read(self.address, self.current.len())?;
+        if live != self.current {
+            bail!(
+                "Patch at {:#X} was overwritten with {live:02X?} since it was applied",
+                self.address
+            );
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, process: &dyn MemoryBackend, data: &[u8]) -> Result<()> {
+        self.verify(process)?;
+        if self.code {
+            process.write_code_bytes(self.address, data)?;
+        } else {
+            process.write_bytes(self.address, data)?;
+        }
+        self.current = data.to_vec();
+        Ok(())
+    }
+
+    pub fn restore(&mut self, process: &dyn MemoryBackend) -> Result<()> {
+        let original = self.original.clone();
+        self.write(process, &original)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_overlaps_between_owners() {
+        let mut registry = PatchRegistry::default();
+        registry.register("First", 0x1000, vec![0; 4], vec![1; 4], false);
+
+        assert_eq!(
+            registry.check("Second", 0x1002, 4).unwrap_err().to_string(),
+            "Patch at 0x1002 overlaps the patch at 0x1000 owned by First"
+        );
+        assert!(registry.check("Second", 0xFFD, 4).is_err());
+        assert!(registry.check("Second", 0xFFC, 4).is_ok());
+        assert!(registry.check("Second", 0x1004, 4).is_ok());
+        assert!(registry.check("First", 0x1002, 4).is_ok());
+    }
+}
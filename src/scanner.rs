@@ -0,0 +1,483 @@
+use crate::cache::ScanCache;
+use crate::memory::{MemoryBackend, MemoryRegion};
+use crate::pe::PeImage;
+use crate::signature::Signature;
+use crate::x86;
+use aho_corasick::AhoCorasick;
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use std::fmt::{self, Display, Formatter};
+
+const CHUNK_SIZE: usize = 0x10_0000;
+// Unique signatures stop collecting after this many matches, enough to report the ambiguity
+const MAX_MATCHES: usize = 16;
+
+pub enum Section {
+    Code,
+    Heap,
+}
+
+impl Section {
+    // Sections of the main image are at the same offsets every launch of a build, the section is part of the key
+    // since a pattern can match elsewhere in another one
+    fn cache_key(&self, signature: &Signature) -> Option<String> {
+        match self {
+            Section::Code => Some(format!(".text {signature}")),
+            Section::Heap => None,
+        }
+    }
+
+    fn range(&self, image: &Result<PeImage>) -> Result<(usize, usize)> {
+        let name = match self {
+            Section::Code => ".text",
+            Section::Heap => return Ok((0, usize::MAX)),
+        };
+
+        match image {
+            Ok(image) => {
+                let section = image.section(name)?;
+                Ok((section.address, section.address + section.size))
+            }
+            Err(error) => bail!("Couldn't read the main module headers ({error})"),
+        }
+    }
+}
+
+// The pattern may still appear later, e.g. once the game has created its heap objects
+#[derive(Debug)]
+pub struct PatternNotFound(String);
+
+impl Display for PatternNotFound {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "Couldn't find pattern \"{}\"", self.0)
+    }
+}
+
+impl std::error::Error for PatternNotFound {}
+
+pub enum Matches {
+    Unique,
+    Several,
+}
+
+#[derive(Clone, Copy)]
+pub struct ScanId(usize);
+
+#[derive(Default)]
+pub struct Scanner {
+    targets: Vec<(Section, Matches, Result<Signature>)>,
+}
+
+struct Pending {
+    id: ScanId,
+    start: usize,
+    end: usize,
+    expected: Matches,
+    signature: Signature,
+    matches: Vec<Match>,
+    cache_key: Option<String>,
+}
+
+impl Pending {
+    fn full(&self) -> bool {
+        match self.expected {
+            Matches::Unique => self.matches.len() == MAX_MATCHES,
+            Matches::Several => false,
+        }
+    }
+}
+
+impl Scanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, section: Section, expected: Matches, pattern: &str) -> ScanId {
+        self.targets
+            .push((section, expected, Signature::new(pattern)));
+        ScanId(self.targets.len() - 1)
+    }
+
+    pub fn scan(self, process: &dyn MemoryBackend) -> ScanResults {
+        self.scan_with(process, None)
+    }
+
+    pub fn scan_cached(self, process: &dyn MemoryBackend, cache: &mut ScanCache) -> ScanResults {
+        self.scan_with(process, Some(cache))
+    }
+
+    fn scan_with(
+        self,
+        process: &dyn MemoryBackend,
+        mut cache: Option<&mut ScanCache>,
+    ) -> ScanResults {
+        let mut results = Vec::with_capacity(self.targets.len());
+        let mut pending = Vec::new();
+        let image = process.main_image();
+        let base = process.module_base();
+
+        for (index, (section, expected, signature)) in self.targets.into_iter().enumerate() {
+            match signature.and_then(|signature| Ok((signature, section.range(&image)?))) {
+                Ok((signature, (start, end))) => {
+                    let cache_key = section.cache_key(&signature);
+                    let cached =
+                        cache
+                            .as_deref_mut()
+                            .zip(cache_key.as_deref())
+                            .and_then(|(cache, key)| {
+                                cached_matches(cache, process, &signature, key, base, start, end)
+                            });
+                    if let Some(matches) = cached {
+                        results.push(Some(Ok(matches)));
+                        continue;
+                    }
+
+                    pending.push(Pending {
+                        id: ScanId(index),
+                        start,
+                        end,
+                        expected,
+                        signature,
+                        matches: Vec::new(),
+                        cache_key,
+                    });
+                    results.push(None);
+                }
+                Err(error) => results.push(Some(Err(error))),
+            }
+        }
+
+        let error = Self::scan_regions(process, &mut pending).err();
+        for pending in pending {
+            let signature = &pending.signature;
+            let count = pending.matches.len();
+            if count > 0 {
+                info!("Found {count} matches for pattern \"{signature}\"");
+            }
+            let result = match (&error, &pending.expected, count) {
+                (Some(error), _, 0) => Err(anyhow!(
+                    "Couldn't scan for pattern \"{signature}\" ({error})"
+                )),
+                (None, _, 0) => Err(PatternNotFound(signature.to_string()).into()),
+                (_, Matches::Unique, 2..) => {
+                    let addresses = pending
+                        .matches
+                        .iter()
+                        .map(|hit| format!("{:#X}", hit.address))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let count = match count {
+                        MAX_MATCHES => format!("{count}+"),
+                        _ => count.to_string(),
+                    };
+                    Err(anyhow!(
+                        "Ambiguous signature \"{signature}\" ({count} matches at {addresses})"
+                    ))
+                }
+                _ => {
+                    if let (Some(cache), Some(key)) = (cache.as_deref_mut(), &pending.cache_key) {
+                        let offsets = pending
+                            .matches
+                            .iter()
+                            .map(|hit| hit.address - base)
+                            .collect();
+                        cache.insert(key, offsets);
+                    }
+                    Ok(pending.matches)
+                }
+            };
+            results[pending.id.0] = Some(result);
+        }
+
+        ScanResults { results }
+    }
+
+    fn scan_regions(process: &dyn MemoryBackend, pending: &mut [Pending]) -> Result<()> {
+        let Some(start) = pending.iter().map(|pending| pending.start).min() else {
+            return Ok(());
+        };
+        let end = pending
+            .iter()
+            .map(|pending| pending.end)
+            .max()
+            .unwrap_or(start);
+
+        let anchors = AhoCorasick::new(pending.iter().map(|pending| pending.signature.anchor().1))?;
+        let overlap = pending
+            .iter()
+            .map(|pending| pending.signature.len() - 1)
+            .max()
+            .unwrap_or_default();
+        let mut remaining = pending.len();
+        info!("Scanning for {remaining} signatures from {start:#X} to {end:#X}");
+
+        let mut buffer = Vec::with_capacity(CHUNK_SIZE + overlap);
+        let mut contiguous = MemoryRegion {
+            address: 0,
+            size: 0,
+            readable: true,
+        };
+
+        for region in process
+            .regions(start)
+            .into_iter()
+            .take_while(|region| region.address < end)
+            .filter(|region| region.readable)
+        {
+            if region.address == contiguous.address + contiguous.size {
+                contiguous.size += region.size;
+            } else {
+                contiguous = region;
+                buffer.clear();
+            }
+
+            let region_end = (region.address + region.size).min(end);
+            let mut address = region.address;
+            while address < region_end {
+                let size = CHUNK_SIZE.min(region_end - address);
+                let kept = overlap.min(buffer.len());
+                buffer.drain(..(buffer.len() - kept));
+                buffer.resize(kept + size, 0);
+
+                if let Err(error) = process.read_bytes(address, &mut buffer[kept..]) {
+                    warn!("Couldn't read process memory at {address:#X} ({error})");
+                    buffer.clear();
+                    contiguous.size = 0;
+                    contiguous.address = 0;
+                    break;
+                }
+                let buffer_address = address - kept;
+                address += size;
+
+                for hit in anchors.find_overlapping_iter(&buffer) {
+                    let pending = &mut pending[hit.pattern().as_usize()];
+                    let (anchor_offset, _) = pending.signature.anchor();
+                    let Some(offset) = hit.start().checked_sub(anchor_offset) else {
+                        continue;
+                    };
+
+                    // Matches that end inside the kept overlap were already seen in the last chunk
+                    if pending.full()
+                        || offset + pending.signature.len() <= kept
+                        || buffer_address + offset < pending.start
+                        || buffer_address + offset + pending.signature.len() > pending.end
+                        || !pending.signature.matches(&buffer[offset..])
+                    {
+                        continue;
+                    }
+
+                    pending.matches.push(Match {
+                        address: buffer_address + offset,
+                        data: buffer[offset..(offset + pending.signature.len())].to_vec(),
+                    });
+
+                    if pending.full() {
+                        remaining -= 1;
+                        if remaining == 0 {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Re-reads cached matches, they're only used if all of them still match
+fn cached_matches(
+    cache: &mut ScanCache,
+    process: &dyn MemoryBackend,
+    signature: &Signature,
+    key: &str,
+    base: usize,
+    start: usize,
+    end: usize,
+) -> Option<Vec<Match>> {
+    let offsets = cache.get(key)?;
+    let matches = offsets
+        .iter()
+        .map(|offset| {
+            let address = base.checked_add(*offset)?;
+            if address < start || address + signature.len() > end {
+                return None;
+            }
+            let data = process.read(address, signature.len()).ok()?;
+            signature.matches(&data).then_some(Match { address, data })
+        })
+        .collect::<Option<Vec<_>>>()
+        .filter(|matches| !matches.is_empty());
+
+    match &matches {
+        Some(matches) => info!(
+            "Using {} cached matches for pattern \"{signature}\"",
+            matches.len()
+        ),
+        None => {
+            info!("Cached matches for pattern \"{signature}\" are stale, scanning again");
+            cache.remove(key);
+        }
+    }
+    matches
+}
+
+pub struct Match {
+    pub address: usize,
+    pub data: Vec<u8>,
+}
+
+impl Match {
+    pub fn rip_relative(
+        &self,
+        instruction_offset: usize,
+        operand_offset: usize,
+        instruction_length: usize,
+    ) -> Result<RipRelative> {
+        let offset = instruction_offset + operand_offset;
+        let Some(displacement) = self.data.get(offset..(offset + 4)) else {
+            bail!(
+                "Displacement at offset {offset} is outside of the {} matched bytes",
+                self.data.len()
+            );
+        };
+
+        Ok(RipRelative {
+            next_instruction: self.address + instruction_offset + instruction_length,
+            displacement: i32::from_le_bytes(displacement.try_into()?),
+        })
+    }
+}
+
+pub struct RipRelative {
+    pub next_instruction: usize,
+    pub displacement: i32,
+}
+
+impl RipRelative {
+    pub fn target(&self) -> usize {
+        self.next_instruction
+            .wrapping_add(self.displacement as isize as usize)
+    }
+
+    // Displacement that makes the operand point at another target
+    pub fn encode(&self, target: usize) -> Result<[u8; 4]> {
+        x86::rel32(self.next_instruction, target)
+    }
+}
+
+pub struct ScanResults {
+    results: Vec<Option<Result<Vec<Match>>>>,
+}
+
+impl ScanResults {
+    pub fn take(&mut self, id: ScanId) -> Result<Match> {
+        Ok(self.take_all(id)?.remove(0))
+    }
+
+    pub fn take_all(&mut self, id: ScanId) -> Result<Vec<Match>> {
+        self.results[id.0]
+            .take()
+            .unwrap_or_else(|| Err(anyhow!("Scan result was already taken")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::BufferBackend;
+
+    const HEAP: usize = 0x10000;
+    const PATTERN: [u8; 8] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+
+    fn scan(process: &BufferBackend, pattern: &str) -> Result<Vec<Match>> {
+        let mut scanner = Scanner::new();
+        let id = scanner.add(Section::Heap, Matches::Several, pattern);
+        scanner.scan(process).take_all(id)
+    }
+
+    fn addresses(matches: &[Match]) -> Vec<usize> {
+        matches.iter().map(|hit| hit.address).collect()
+    }
+
+    #[test]
+    fn finds_matches_straddling_chunks() {
+        let mut data = vec![0; 2 * CHUNK_SIZE];
+        data[(CHUNK_SIZE - 3)..(CHUNK_SIZE + 5)].copy_from_slice(&PATTERN);
+        data[0x10..0x18].copy_from_slice(&PATTERN);
+        let process = BufferBackend::new(HEAP).with_region(HEAP, data);
+
+        let matches = scan(&process, "11 22 33 44 55 66 77 88").unwrap();
+        assert_eq!(addresses(&matches), [HEAP + 0x10, HEAP + CHUNK_SIZE - 3]);
+        assert_eq!(matches[1].data, PATTERN);
+    }
+
+    #[test]
+    fn finds_matches_straddling_adjacent_regions() {
+        let mut first = vec![0; 0x1000];
+        first[0xFFC..].copy_from_slice(&PATTERN[..4]);
+        let mut second = vec![0; 0x1000];
+        second[..4].copy_from_slice(&PATTERN[4..]);
+        let process = BufferBackend::new(HEAP)
+            .with_region(HEAP, first.clone())
+            .with_region(HEAP + 0x1000, second.clone());
+
+        let matches = scan(&process, "11 22 33 44 ?? ?? 77 88").unwrap();
+        assert_eq!(addresses(&matches), [HEAP + 0xFFC]);
+
+        // A gap between the regions means the bytes aren't contiguous in memory
+        let process = BufferBackend::new(HEAP)
+            .with_region(HEAP, first)
+            .with_region(HEAP + 0x2000, second);
+        assert!(scan(&process, "11 22 33 44 ?? ?? 77 88").is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_unique_signatures() {
+        let mut data = vec![0; 0x100];
+        data[0x10..0x18].copy_from_slice(&PATTERN);
+        data[0x40..0x48].copy_from_slice(&PATTERN);
+        let process = BufferBackend::new(HEAP).with_region(HEAP, data);
+
+        let mut scanner = Scanner::new();
+        let unique = scanner.add(Section::Heap, Matches::Unique, "11 22 33 44");
+        let missing = scanner.add(Section::Heap, Matches::Unique, "11 22 33 45");
+        let mut results = scanner.scan(&process);
+        assert!(results
+            .take(unique)
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Ambiguous signature \"11 22 33 44\" (2 matches"));
+        assert!(results.take(missing).err().unwrap().is::<PatternNotFound>());
+        assert!(!results.take(missing).err().unwrap().is::<PatternNotFound>());
+    }
+
+    #[test]
+    fn returns_every_match_of_several() {
+        let mut data = vec![0; 0x1000];
+        for index in 0..40 {
+            data[(index * 0x40)..(index * 0x40 + 8)].copy_from_slice(&PATTERN);
+        }
+        let process = BufferBackend::new(HEAP).with_region(HEAP, data);
+
+        let matches = scan(&process, "11 22 33 44 55 66 77 88").unwrap();
+        assert_eq!(matches.len(), 40);
+        assert_eq!(matches[39].address, HEAP + 39 * 0x40);
+    }
+
+    #[test]
+    fn decodes_and_encodes_rip_relative_operands() {
+        let hit = Match {
+            address: 0x1000,
+            data: vec![0xF3, 0x0F, 0x10, 0x25, 0xF8, 0x0F, 0x00, 0x00],
+        };
+        let operand = hit.rip_relative(0, 4, 8).unwrap();
+        assert_eq!(operand.next_instruction, 0x1008);
+        assert_eq!(operand.target(), 0x2000);
+        assert_eq!(operand.encode(0x2000).unwrap(), [0xF8, 0x0F, 0x00, 0x00]);
+        assert_eq!(operand.encode(0x1000).unwrap(), (-8i32).to_le_bytes());
+        assert!(operand.encode(0x1_0000_2000).is_err());
+        assert!(hit.rip_relative(2, 4, 8).is_err());
+    }
+}
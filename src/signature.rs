@@ -0,0 +1,158 @@
+use anyhow::{bail, Result};
+use std::fmt::{Display, Formatter};
+
+pub struct Signature {
+    pattern: String,
+    bytes: Vec<u8>,
+    masks: Vec<u8>,
+    anchor_offset: usize,
+    anchor_length: usize,
+}
+
+impl Signature {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut bytes = Vec::new();
+        let mut masks = Vec::new();
+
+        for (index, token) in pattern.split_whitespace().enumerate() {
+            let (byte, mask) = match token.as_bytes() {
+                [b'?'] | [b'?', b'?'] => (0x00, 0x00),
+                [high, low] => {
+                    let (high, high_mask) = parse_nibble(*high, pattern, index, token)?;
+                    let (low, low_mask) = parse_nibble(*low, pattern, index, token)?;
+                    (high << 4 | low, high_mask << 4 | low_mask)
+                }
+                _ => bail!(
+                    "Invalid signature \"{pattern}\": token {} (\"{token}\") must be two hex digits or wildcards",
+                    index + 1
+                ),
+            };
+            bytes.push(byte);
+            masks.push(mask);
+        }
+
+        if bytes.is_empty() {
+            bail!("Invalid signature \"{pattern}\": signature is empty");
+        }
+        if !masks.contains(&0xFF) {
+            bail!("Invalid signature \"{pattern}\": signature needs at least one byte without wildcards");
+        }
+
+        let (anchor_offset, anchor_length) = longest_fixed_run(&masks);
+
+        Ok(Self {
+            pattern: pattern.split_whitespace().collect::<Vec<_>>().join(" "),
+            bytes,
+            masks,
+            anchor_offset,
+            anchor_length,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn anchor(&self) -> (usize, &[u8]) {
+        let anchor = &self.bytes[self.anchor_offset..(self.anchor_offset + self.anchor_length)];
+        (self.anchor_offset, anchor)
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() >= self.bytes.len()
+            && self
+                .bytes
+                .iter()
+                .zip(&self.masks)
+                .zip(data)
+                .all(|((byte, mask), data)| data & mask == *byte)
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str(&self.pattern)
+    }
+}
+
+fn parse_nibble(digit: u8, pattern: &str, index: usize, token: &str) -> Result<(u8, u8)> {
+    match digit {
+        b'?' => Ok((0x0, 0x0)),
+        b'0'..=b'9' => Ok((digit - b'0', 0xF)),
+        b'a'..=b'f' => Ok((digit - b'a' + 10, 0xF)),
+        b'A'..=b'F' => Ok((digit - b'A' + 10, 0xF)),
+        _ => bail!(
+            "Invalid signature \"{pattern}\": token {} (\"{token}\") contains invalid hex digit '{}'",
+            index + 1,
+            digit as char
+        ),
+    }
+}
+
+fn longest_fixed_run(masks: &[u8]) -> (usize, usize) {
+    let mut longest = (0, 0);
+    let mut start = 0;
+    for (index, &mask) in masks.iter().enumerate() {
+        if mask != 0xFF {
+            start = index + 1;
+        } else if index + 1 - start > longest.1 {
+            longest = (start, index + 1 - start);
+        }
+    }
+    longest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bytes_and_wildcards() {
+        let signature = Signature::new("F3 0f 10  25 ?? ? 4? ?C").unwrap();
+        assert_eq!(signature.to_string(), "F3 0f 10 25 ?? ? 4? ?C");
+        assert_eq!(signature.len(), 8);
+        assert_eq!(
+            signature.bytes,
+            [0xF3, 0x0F, 0x10, 0x25, 0x00, 0x00, 0x40, 0x0C]
+        );
+        assert_eq!(
+            signature.masks,
+            [0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xF0, 0x0F]
+        );
+        assert_eq!(signature.anchor(), (0, &[0xF3, 0x0F, 0x10, 0x25][..]));
+    }
+
+    #[test]
+    fn matches_nibble_wildcards() {
+        let signature = Signature::new("48 4? ?C").unwrap();
+        assert!(signature.matches(&[0x48, 0x41, 0x0C]));
+        assert!(signature.matches(&[0x48, 0x4F, 0xFC, 0x00]));
+        assert!(!signature.matches(&[0x48, 0x51, 0x0C]));
+        assert!(!signature.matches(&[0x48, 0x41, 0x0D]));
+        assert!(!signature.matches(&[0x48, 0x41]));
+    }
+
+    #[test]
+    fn anchors_on_longest_fixed_run() {
+        let signature = Signature::new("01 ?? 02 03 04 4? 05 06").unwrap();
+        assert_eq!(signature.anchor(), (2, &[0x02, 0x03, 0x04][..]));
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let error = |pattern| Signature::new(pattern).err().unwrap().to_string();
+        assert_eq!(
+            error("F3 0G"),
+            "Invalid signature \"F3 0G\": token 2 (\"0G\") contains invalid hex digit 'G'"
+        );
+        assert_eq!(
+            error("F3 123"),
+            "Invalid signature \"F3 123\": token 2 (\"123\") must be two hex digits or wildcards"
+        );
+        assert_eq!(error("  "), "Invalid signature \"  \": signature is empty");
+        assert_eq!(
+            error("?? 4?"),
+            "Invalid signature \"?? 4?\": signature needs at least one byte without wildcards"
+        );
+    }
+}
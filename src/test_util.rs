@@ -0,0 +1,38 @@
+use crate::memory::BufferBackend;
+use crate::pe::{DOS_MAGIC, NT_SIGNATURE, PE32_PLUS_MAGIC, SECTION_HEADER_SIZE};
+
+pub const BASE: usize = 0x1_4000_0000;
+const SECTION_ALIGNMENT: usize = 0x1000;
+
+// A PE32+ image with the given sections laid out one page after another
+pub fn image(sections: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut image = vec![0; SECTION_ALIGNMENT];
+    image[..2].copy_from_slice(&DOS_MAGIC.to_le_bytes());
+    image[0x3C..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+    image[0x40..0x44].copy_from_slice(&NT_SIGNATURE.to_le_bytes());
+    image[0x46..0x48].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+    image[0x48..0x4C].copy_from_slice(&0x6512_3456u32.to_le_bytes());
+    image[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+    image[0x58..0x5A].copy_from_slice(&PE32_PLUS_MAGIC.to_le_bytes());
+
+    for (index, (name, data)) in sections.iter().enumerate() {
+        let address = image.len();
+        let header = 0x58 + 240 + index * SECTION_HEADER_SIZE;
+        image[header..(header + name.len())].copy_from_slice(name.as_bytes());
+        image[(header + 8)..(header + 12)].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        image[(header + 12)..(header + 16)].copy_from_slice(&(address as u32).to_le_bytes());
+        image.extend_from_slice(data);
+        image.resize(
+            (image.len() + SECTION_ALIGNMENT - 1) & !(SECTION_ALIGNMENT - 1),
+            0,
+        );
+    }
+
+    let size = image.len() as u32;
+    image[(0x58 + 56)..(0x58 + 60)].copy_from_slice(&size.to_le_bytes());
+    image
+}
+
+pub fn backend(sections: &[(&str, &[u8])]) -> BufferBackend {
+    BufferBackend::new(BASE).with_module("ACMirage.exe", BASE, image(sections))
+}
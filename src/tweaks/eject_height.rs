@@ -1,18 +1,22 @@
 use super::{Tweak, TweakIntent};
 use crate::config::{TweakConfig, CONFIG};
-use crate::game::{Game, Patch};
-use crate::process::Section;
+use crate::game::{Game, PatchHandle, Patches};
+use crate::scanner::{Match, Matches, RipRelative, ScanId, Scanner, Section};
+use crate::x86;
 use anyhow::{bail, Result};
 use log::{error, info};
+use std::mem::size_of;
 use std::ops::DerefMut;
 
 const CONFIG_KEY: &str = "eject-height";
 const INSTRUCTION_SIZE: usize = 8;
+const OPERAND_OFFSET: usize = 4;
 
 pub struct EjectHeightTweak {
     game: Game,
     instruction_address: usize,
-    cave_offset: i32,
+    original_instruction: [u8; INSTRUCTION_SIZE],
+    operand: RipRelative,
     state: State,
     value: f64,
 }
@@ -20,39 +24,44 @@ pub struct EjectHeightTweak {
 enum State {
     Disabled,
     Enabled {
-        _instruction_patch: Patch<[u8; INSTRUCTION_SIZE]>,
-        value_patch: Patch<f32>,
+        patches: Patches,
+        value_patch: PatchHandle<f32>,
     },
 }
 
 impl EjectHeightTweak {
-    pub fn new(game: &Game) -> Result<Self> {
-        let (region, instruction_offset) = game.process.find_pattern(
+    pub fn scan(game: &Game, scanner: &mut Scanner) -> ScanId {
+        scanner.add(
             Section::Code,
-            r"\xF3\x0F\x10\x25[\x00-\xFF]{4}\xF3\x0F\x10\x6C\x24\x58",
-        )?;
-        let instruction_address = region.address + instruction_offset;
-        info!("Found Eject Height instruction at {instruction_address:#X}");
-
-        let mut cave_offset = ((4 - (instruction_address % 4)) % 4) as i32;
-        loop {
-            let offset = instruction_offset + INSTRUCTION_SIZE + cave_offset as usize;
-            if region.data[offset..(offset + 4)] == [0xCC, 0xCC, 0xCC, 0xCC] {
-                let cave_address = region.address + offset;
-                info!("Found code cave for Eject Height at {cave_address:#X}");
-                break;
-            }
+            Matches::Unique,
+            &game.signature(CONFIG_KEY, "F3 0F 10 25 ?? ?? ?? ?? F3 0F 10 6C 24 58"),
+        )
+    }
 
-            if cave_offset as usize + 8 > region.data.len() {
-                bail!("Couldn't find code cave for Eject Height");
-            }
-            cave_offset += 4;
+    pub fn new(game: &Game, scan: Result<Match>) -> Result<Self> {
+        let scan = scan?;
+        let instruction_address = scan.address;
+        let instruction = x86::decode(&scan.data)?;
+        if instruction.length != INSTRUCTION_SIZE {
+            bail!(
+                "Eject Height instruction is {} bytes, expected {INSTRUCTION_SIZE}",
+                instruction.length
+            );
+        }
+        if instruction.relative != Some((OPERAND_OFFSET, size_of::<i32>())) {
+            bail!("Eject Height instruction doesn't read a rip-relative operand at offset {OPERAND_OFFSET}");
         }
+        let operand = scan.rip_relative(0, OPERAND_OFFSET, instruction.length)?;
+        info!(
+            "Found Eject Height instruction at {instruction_address:#X} reading {:#X}",
+            operand.target()
+        );
 
         Ok(Self {
-            game: game.clone(),
+            game: game.owned_by(Self::NAME),
             instruction_address,
-            cave_offset,
+            original_instruction: scan.data[..INSTRUCTION_SIZE].try_into()?,
+            operand,
             state: State::Disabled,
             value: Self::DEFAULT,
         })
@@ -100,25 +109,26 @@ impl EjectHeightTweak {
 
     fn enable(&mut self) -> Result<()> {
         info!("Enabling Eject Height tweak");
-        let full_cave_offset = INSTRUCTION_SIZE + self.cave_offset as usize;
-        let size = full_cave_offset + 4;
-        let memory = self.game.process.read(self.instruction_address, size)?;
-
-        let address = self.instruction_address + full_cave_offset;
         let value = self.value as f32;
-        let original =
-            f32::from_ne_bytes(memory[full_cave_offset..(full_cave_offset + 4)].try_into()?);
-        let value_patch = self.game.patch(address, &value, original)?;
+        let cave = self
+            .game
+            .cave(self.operand.next_instruction, size_of::<f32>(), 4)?;
 
-        let address = self.instruction_address;
-        let cave = self.cave_offset.to_ne_bytes();
-        let instruction = [0xF3, 0x0F, 0x10, 0x25, cave[0], cave[1], cave[2], cave[3]]; // movss xmm4 [cave]
-        let mut original = [0; INSTRUCTION_SIZE];
-        original.copy_from_slice(&memory[..INSTRUCTION_SIZE]);
-        let instruction_patch = self.game.patch(address, &instruction, original)?;
+        // movss xmm4, [rip+cave]
+        let mut instruction = self.original_instruction;
+        instruction[OPERAND_OFFSET..].copy_from_slice(&self.operand.encode(cave.address)?);
+
+        let mut patch_set = self.game.patch_set();
+        let value_patch = patch_set.patch_in_cave(cave, value);
+        patch_set.patch_code(
+            self.instruction_address,
+            instruction,
+            self.original_instruction,
+        );
+        let patches = patch_set.apply()?;
 
         self.state = State::Enabled {
-            _instruction_patch: instruction_patch,
+            patches,
             value_patch,
         };
 
@@ -164,8 +174,15 @@ impl Tweak<f64> for EjectHeightTweak {
 
     fn set_value(&mut self, value: f64) {
         info!("Setting Eject Height to {value}");
-        if let State::Enabled { value_patch, .. } = &self.state {
-            if let Err(error) = value_patch.update(&(value as f32)) {
+        if let State::Enabled {
+            patches,
+            value_patch,
+        } = &self.state
+        {
+            if let Err(error) = patches
+                .get(value_patch)
+                .and_then(|patch| patch.update(&(value as f32)))
+            {
                 error!("Failed to set Eject Height: {error}");
                 return;
             }
@@ -176,8 +193,15 @@ impl Tweak<f64> for EjectHeightTweak {
 
     fn reset_value(&mut self) {
         info!("Resetting Eject Height to {}", Self::DEFAULT);
-        if let State::Enabled { value_patch, .. } = &self.state {
-            if let Err(error) = value_patch.update(&(Self::DEFAULT as f32)) {
+        if let State::Enabled {
+            patches,
+            value_patch,
+        } = &self.state
+        {
+            if let Err(error) = patches
+                .get(value_patch)
+                .and_then(|patch| patch.update(&(Self::DEFAULT as f32)))
+            {
                 error!("Failed to reset Eject Height: {error}");
                 return;
             }
@@ -186,3 +210,67 @@ impl Tweak<f64> for EjectHeightTweak {
         self.save_config();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{backend, BASE};
+    use std::sync::Arc;
+
+    // movss xmm4, [rip+0xFF8]; movss xmm5, [rsp+0x58]; ret; followed by padding
+    const CODE: [u8; 15] = [
+        0xF3, 0x0F, 0x10, 0x25, 0xF8, 0x0F, 0x00, 0x00, 0xF3, 0x0F, 0x10, 0x6C, 0x24, 0x58, 0xC3,
+    ];
+
+    fn tweak() -> (Game, EjectHeightTweak) {
+        let mut code = CODE.to_vec();
+        code.resize(0x100, 0xCC);
+        let mut rdata = vec![0; 0x10];
+        rdata[..4].copy_from_slice(&1.3f32.to_le_bytes());
+        let game = Game::new(Arc::new(backend(&[(".text", &code), (".rdata", &rdata)])));
+
+        let mut scanner = Scanner::new();
+        let id = EjectHeightTweak::scan(&game, &mut scanner);
+        let mut results = scanner.scan(game.process.as_ref());
+        let tweak = EjectHeightTweak::new(&game, results.take(id)).unwrap();
+        (game, tweak)
+    }
+
+    #[test]
+    fn finds_instruction_and_operand() {
+        let (_, tweak) = tweak();
+        assert_eq!(tweak.instruction_address, BASE + 0x1000);
+        assert_eq!(tweak.operand.target(), BASE + 0x2000);
+        assert_eq!(tweak.original_instruction, CODE[..INSTRUCTION_SIZE]);
+    }
+
+    #[test]
+    fn enable_update_and_disable() {
+        let (game, mut tweak) = tweak();
+        let process = game.process.as_ref();
+        tweak.value = 2.0;
+        tweak.enable().unwrap();
+
+        let instruction = process.read(BASE + 0x1000, INSTRUCTION_SIZE).unwrap();
+        assert_eq!(instruction[..4], [0xF3, 0x0F, 0x10, 0x25]);
+        let displacement = i32::from_le_bytes(instruction[4..].try_into().unwrap());
+        let cave = (BASE + 0x1008).wrapping_add(displacement as isize as usize);
+        assert_ne!(cave, BASE + 0x2000);
+        assert_eq!(process.read_into::<f32>(cave).unwrap(), 2.0);
+
+        let State::Enabled {
+            patches,
+            value_patch,
+        } = &tweak.state
+        else {
+            panic!("Tweak isn't enabled");
+        };
+        patches.get(value_patch).unwrap().update(&4.5f32).unwrap();
+        assert_eq!(process.read_into::<f32>(cave).unwrap(), 4.5);
+
+        tweak.state = State::Disabled;
+        assert_eq!(process.read(BASE + 0x1000, CODE.len()).unwrap(), CODE);
+        assert_eq!(process.read(cave, 4).unwrap(), [0xCC; 4]);
+        assert!(game.patches().is_empty());
+    }
+}
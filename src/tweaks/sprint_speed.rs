@@ -1,7 +1,7 @@
 use super::{Tweak, TweakIntent};
 use crate::config::{TweakConfig, CONFIG};
-use crate::game::{Game, Patch};
-use crate::process::Section;
+use crate::game::{Game, PatchHandle, Patches};
+use crate::scanner::{Match, Matches, ScanId, Scanner, Section};
 use anyhow::Result;
 use log::{error, info};
 use std::ops::DerefMut;
@@ -10,27 +10,46 @@ const CONFIG_KEY: &str = "sprint-speed";
 
 pub struct SprintSpeedTweak {
     game: Game,
-    address: usize,
+    addresses: Vec<usize>,
     state: State,
     value: f64,
 }
 
 enum State {
     Disabled,
-    Enabled { patch: Patch<f32> },
+    Enabled {
+        patches: Patches,
+        value_patches: Vec<PatchHandle<f32>>,
+    },
 }
 
 impl SprintSpeedTweak {
-    pub fn new(game: &Game) -> Result<Self> {
-        let (region, offset) = game.process.find_pattern(
+    pub fn scan(game: &Game, scanner: &mut Scanner) -> ScanId {
+        scanner.add(
             Section::Heap,
-            r"\x00\x00\x00\x00\x33\xFF\x33\x3E\x9A\x99\xD9\x40\x00\x00\x00\x00",
-        )?;
-        let address = region.address + offset + 8;
+            Matches::Several,
+            &game.signature(
+                CONFIG_KEY,
+                "00 00 00 00 33 FF 33 3E 9A 99 D9 40 00 00 00 00",
+            ),
+        )
+    }
+
+    // Every copy of the value is patched, they all hold the default since it's part of the signature
+    pub fn new(game: &Game, scan: Result<Vec<Match>>) -> Result<Self> {
+        let expression = game.address(CONFIG_KEY);
+        let addresses = match expression {
+            Some(expression) => {
+                let address = game.resolve(&expression)?;
+                info!("Resolved Sprint Speed address \"{expression}\" to {address:#X}");
+                vec![address]
+            }
+            None => scan?.iter().map(|hit| hit.address + 8).collect(),
+        };
 
         Ok(Self {
-            game: game.clone(),
-            address,
+            game: game.owned_by(Self::NAME),
+            addresses,
             state: State::Disabled,
             value: Self::DEFAULT,
         })
@@ -79,9 +98,30 @@ impl SprintSpeedTweak {
     fn enable(&mut self) -> Result<()> {
         info!("Enabling Sprint Speed tweak");
         let value = self.value as f32;
-        let original = self.game.process.read_into(self.address)?;
-        let patch = self.game.patch(self.address, &value, original)?;
-        self.state = State::Enabled { patch };
+        let mut patch_set = self.game.patch_set();
+        let value_patches = self
+            .addresses
+            .iter()
+            .map(|&address| patch_set.patch(address, value, Self::DEFAULT as f32))
+            .collect();
+        let patches = patch_set.apply()?;
+        self.state = State::Enabled {
+            patches,
+            value_patches,
+        };
+        Ok(())
+    }
+
+    fn update(&self, value: f32) -> Result<()> {
+        if let State::Enabled {
+            patches,
+            value_patches,
+        } = &self.state
+        {
+            for value_patch in value_patches {
+                patches.get(value_patch)?.update(&value)?;
+            }
+        }
         Ok(())
     }
 }
@@ -124,11 +164,9 @@ impl Tweak<f64> for SprintSpeedTweak {
 
     fn set_value(&mut self, value: f64) {
         info!("Setting Sprint Speed to {value}");
-        if let State::Enabled { patch, .. } = &self.state {
-            if let Err(error) = patch.update(&(value as f32)) {
-                error!("Failed to set Sprint Speed: {error}");
-                return;
-            }
+        if let Err(error) = self.update(value as f32) {
+            error!("Failed to set Sprint Speed: {error}");
+            return;
         }
         self.value = value;
         self.save_config();
@@ -136,13 +174,70 @@ impl Tweak<f64> for SprintSpeedTweak {
 
     fn reset_value(&mut self) {
         info!("Resetting Sprint Speed to {}", Self::DEFAULT);
-        if let State::Enabled { patch, .. } = &self.state {
-            if let Err(error) = patch.update(&(Self::DEFAULT as f32)) {
-                error!("Failed to reset Sprint Speed: {error}");
-                return;
-            }
+        if let Err(error) = self.update(Self::DEFAULT as f32) {
+            error!("Failed to reset Sprint Speed: {error}");
+            return;
         }
         self.value = Self::DEFAULT;
         self.save_config();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::backend;
+    use std::sync::Arc;
+
+    const HEAP: usize = 0x2_0000_0000;
+
+    // Two copies of the sprint settings on the heap
+    fn tweak() -> (Game, SprintSpeedTweak) {
+        let mut heap = vec![0; 0x40];
+        for offset in [0x10, 0x28] {
+            heap[offset..(offset + 16)].copy_from_slice(&[
+                0x00, 0x00, 0x00, 0x00, 0x33, 0xFF, 0x33, 0x3E, 0x9A, 0x99, 0xD9, 0x40, 0x00, 0x00,
+                0x00, 0x00,
+            ]);
+        }
+        let process = backend(&[(".text", &[0xCC; 0x10])]).with_region(HEAP, heap);
+        let game = Game::new(Arc::new(process));
+
+        let mut scanner = Scanner::new();
+        let id = SprintSpeedTweak::scan(&game, &mut scanner);
+        let mut results = scanner.scan(game.process.as_ref());
+        let tweak = SprintSpeedTweak::new(&game, results.take_all(id)).unwrap();
+        (game, tweak)
+    }
+
+    #[test]
+    fn enable_update_and_disable_every_copy() {
+        let (game, mut tweak) = tweak();
+        assert_eq!(tweak.addresses, [HEAP + 0x18, HEAP + 0x30]);
+        let process = game.process.as_ref();
+        let values =
+            || [HEAP + 0x18, HEAP + 0x30].map(|address| process.read_into::<f32>(address).unwrap());
+
+        tweak.value = 9.0;
+        tweak.enable().unwrap();
+        assert_eq!(values(), [9.0, 9.0]);
+        tweak.update(10.5).unwrap();
+        assert_eq!(values(), [10.5, 10.5]);
+
+        tweak.state = State::Disabled;
+        assert_eq!(values(), [6.8, 6.8]);
+    }
+
+    #[test]
+    fn refuses_to_enable_if_a_copy_changed() {
+        let (game, mut tweak) = tweak();
+        let process = game.process.as_ref();
+        process
+            .write_bytes(HEAP + 0x30, &7.5f32.to_le_bytes())
+            .unwrap();
+
+        assert!(tweak.enable().is_err());
+        assert_eq!(process.read_into::<f32>(HEAP + 0x18).unwrap(), 6.8);
+        assert!(game.patches().is_empty());
+    }
+}
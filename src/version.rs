@@ -0,0 +1,253 @@
+use crate::memory::MemoryBackend;
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::Read;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01B3;
+const SECONDS_PER_DAY: i64 = 86400;
+
+pub static VERSIONS: Lazy<Vec<Version>> =
+    Lazy::new(
+        || match toml::from_str::<Versions>(include_str!("../versions.toml")) {
+            Ok(versions) => versions.versions,
+            Err(error) => {
+                warn!("Couldn't parse the bundled version table ({error})");
+                Vec::new()
+            }
+        },
+    );
+
+#[derive(Deserialize)]
+struct Versions {
+    #[serde(default)]
+    versions: Vec<Version>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Version {
+    pub name: String,
+    pub tested: String,
+    pub timestamp: u32,
+    #[serde(rename = "image-size")]
+    pub image_size: u32,
+    pub hash: Option<String>,
+    #[serde(default)]
+    pub signatures: HashMap<String, String>,
+    #[serde(default)]
+    pub addresses: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fingerprint {
+    pub timestamp: u32,
+    pub image_size: u32,
+    pub hash: Option<u64>,
+}
+
+impl Fingerprint {
+    pub fn read(process: &dyn MemoryBackend) -> Result<Self> {
+        let image = process.main_image()?;
+        let base = process.module_base();
+        let hash = process
+            .modules()
+            .and_then(|modules| {
+                let module = modules
+                    .into_iter()
+                    .find(|module| module.base == base)
+                    .ok_or_else(|| anyhow!("Couldn't find the main module at {base:#X}"))?;
+                hash_file(&module.path)
+            })
+            .map_err(|error| warn!("Couldn't hash the game executable ({error})"))
+            .ok();
+
+        Ok(Self {
+            timestamp: image.timestamp,
+            image_size: image.image_size,
+            hash,
+        })
+    }
+
+    pub fn version(&self) -> Option<&'static Version> {
+        VERSIONS.iter().find(|version| self.matches(version))
+    }
+
+    fn matches(&self, version: &Version) -> bool {
+        let hash = match (&version.hash, self.hash) {
+            (Some(expected), Some(hash)) => {
+                let expected = expected.trim_start_matches("0x");
+                u64::from_str_radix(expected, 16).ok() == Some(hash)
+            }
+            _ => true,
+        };
+        version.timestamp == self.timestamp && version.image_size == self.image_size && hash
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "built {} (timestamp {:#X}, image size {:#X}",
+            date(self.timestamp),
+            self.timestamp,
+            self.image_size
+        )?;
+        if let Some(hash) = self.hash {
+            write!(formatter, ", hash {hash:016X}")?;
+        }
+        write!(formatter, ")")
+    }
+}
+
+// The tested version of the build, nothing to check against if the table is empty
+pub fn check<'a>(
+    fingerprint: Option<&Fingerprint>,
+    versions: &'a [Version],
+) -> Result<Option<&'a Version>> {
+    let Some(latest) = versions.last() else {
+        return Ok(None);
+    };
+    let Some(fingerprint) = fingerprint else {
+        bail!(
+            "Couldn't identify the game version, last tested {}",
+            latest.name
+        );
+    };
+    match versions.iter().find(|version| fingerprint.matches(version)) {
+        Some(version) => Ok(Some(version)),
+        None => bail!(
+            "Unsupported game version {fingerprint}, last tested {} on {}",
+            latest.name,
+            latest.tested
+        ),
+    }
+}
+
+fn hash_file(path: &str) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 0x100000];
+    let mut hash = FNV_OFFSET_BASIS;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hash);
+        }
+        for &byte in &buffer[..read] {
+            hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+// Civil date of a Unix timestamp, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn date(timestamp: u32) -> String {
+    let days = timestamp as i64 / SECONDS_PER_DAY + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = r#"
+        [[versions]]
+        name = "1.0.0"
+        tested = "2023-10-05"
+        timestamp = 0x65123456
+        image-size = 0x6A3C000
+        signatures = { eject-height = "F3 0F 10 25" }
+
+        [[versions]]
+        name = "1.0.1"
+        tested = "2023-10-20"
+        timestamp = 0x65300000
+        image-size = 0x6A40000
+        hash = "0x0123456789ABCDEF"
+    "#;
+
+    fn fingerprint(timestamp: u32, image_size: u32, hash: Option<u64>) -> Fingerprint {
+        Fingerprint {
+            timestamp,
+            image_size,
+            hash,
+        }
+    }
+
+    #[test]
+    fn parses_bundled_table() {
+        assert!(toml::from_str::<Versions>(include_str!("../versions.toml")).is_ok());
+    }
+
+    #[test]
+    fn matches_versions() {
+        let versions = toml::from_str::<Versions>(TABLE).unwrap().versions;
+        let find = |fingerprint: Fingerprint| {
+            versions
+                .iter()
+                .find(|version| fingerprint.matches(version))
+                .map(|version| version.name.as_str())
+        };
+
+        assert_eq!(
+            find(fingerprint(0x65123456, 0x6A3C000, Some(1))),
+            Some("1.0.0")
+        );
+        assert_eq!(versions[0].signatures["eject-height"], "F3 0F 10 25");
+        assert_eq!(
+            find(fingerprint(0x65300000, 0x6A40000, None)),
+            Some("1.0.1")
+        );
+        assert_eq!(
+            find(fingerprint(0x65300000, 0x6A40000, Some(0x0123456789ABCDEF))),
+            Some("1.0.1")
+        );
+        assert_eq!(find(fingerprint(0x65300000, 0x6A40000, Some(1))), None);
+        assert_eq!(find(fingerprint(0x65123456, 0x6A40000, None)), None);
+    }
+
+    #[test]
+    fn checks_fingerprints_against_the_table() {
+        let versions = toml::from_str::<Versions>(TABLE).unwrap().versions;
+        let supported = fingerprint(0x65123456, 0x6A3C000, None);
+        let version = check(Some(&supported), &versions).unwrap().unwrap();
+        assert_eq!(version.name, "1.0.0");
+
+        let unsupported = fingerprint(0x65400000, 0x6A40000, None);
+        assert_eq!(
+            check(Some(&unsupported), &versions).err().unwrap().to_string(),
+            "Unsupported game version built 2023-10-30 (timestamp 0x65400000, image size 0x6A40000), last tested 1.0.1 on 2023-10-20"
+        );
+        assert!(check(None, &versions).is_err());
+        assert!(check(Some(&unsupported), &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn displays_build_details() {
+        assert_eq!(
+            fingerprint(0x65123456, 0x6A3C000, None).to_string(),
+            "built 2023-09-26 (timestamp 0x65123456, image size 0x6A3C000)"
+        );
+        assert_eq!(
+            fingerprint(0, 0x1000, Some(0xAB)).to_string(),
+            "built 1970-01-01 (timestamp 0x0, image size 0x1000, hash 00000000000000AB)"
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use anyhow::{bail, Result};
+
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Map {
+    OneByte,
+    TwoByte,
+    ThreeByte38,
+    ThreeByte3A,
+}
+
+#[derive(Clone, Copy)]
+enum Immediate {
+    None,
+    Byte,
+    Word,
+    Dword,
+    // 16 or 32 bits depending on the operand size
+    Full,
+    Enter,
+    // 16, 32 or 64 bits, only for mov r, imm
+    Quad,
+    // 32 or 64 bits depending on the address size
+    Offset,
+}
+
+pub struct Instruction {
+    pub length: usize,
+    // Offset and size of a displacement relative to the end of the instruction
+    pub relative: Option<(usize, usize)>,
+}
+
+pub fn decode(code: &[u8]) -> Result<Instruction> {
+    let mut decoder = Decoder {
+        code,
+        position: 0,
+        relative: None,
+    };
+    let length = decoder.instruction()?;
+    Ok(Instruction {
+        length,
+        relative: decoder.relative,
+    })
+}
+
+pub fn instruction_length(code: &[u8]) -> Result<usize> {
+    Ok(decode(code)?.length)
+}
+
+// Length of the instructions starting at code that cover at least minimum bytes
+pub fn covering_length(code: &[u8], minimum: usize) -> Result<usize> {
+    let mut length = 0;
+    while length < minimum {
+        length += instruction_length(&code[length..])?;
+    }
+    Ok(length)
+}
+
+struct Decoder<'a> {
+    code: &'a [u8],
+    position: usize,
+    relative: Option<(usize, usize)>,
+}
+
+impl Decoder<'_> {
+    fn next(&mut self) -> Result<u8> {
+        let Some(&byte) = self.code.get(self.position) else {
+            bail!("Instruction {:02X?} is truncated", self.code);
+        };
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn peek(&self) -> Result<u8> {
+        match self.code.get(self.position) {
+            Some(&byte) => Ok(byte),
+            None => bail!("Instruction {:02X?} is truncated", self.code),
+        }
+    }
+
+    fn skip(&mut self, count: usize) -> Result<()> {
+        if self.position + count > self.code.len() {
+            bail!("Instruction {:02X?} is truncated", self.code);
+        }
+        self.position += count;
+        Ok(())
+    }
+
+    fn instruction(&mut self) -> Result<usize> {
+        let mut operand_size_prefix = false;
+        let mut address_size_prefix = false;
+        loop {
+            match self.peek()? {
+                0x66 => operand_size_prefix = true,
+                0x67 => address_size_prefix = true,
+                0xF0 | 0xF2 | 0xF3 | 0x2E | 0x36 | 0x3E | 0x26 | 0x64 | 0x65 => {}
+                _ => break,
+            }
+            self.position += 1;
+        }
+
+        let mut rex_w = false;
+        if let 0x40..=0x4F = self.peek()? {
+            rex_w = self.next()? & 0x08 != 0;
+        }
+
+        let opcode = self.next()?;
+        let (map, opcode) = match opcode {
+            0xC4 | 0xC5 | 0x62 => return self.vector(opcode),
+            0x0F => match self.next()? {
+                0x38 => (Map::ThreeByte38, self.next()?),
+                0x3A => (Map::ThreeByte3A, self.next()?),
+                opcode => (Map::TwoByte, opcode),
+            },
+            opcode => (Map::OneByte, opcode),
+        };
+
+        let (has_modrm, mut immediate) = match map {
+            Map::OneByte => one_byte(opcode)?,
+            Map::TwoByte => two_byte(opcode)?,
+            Map::ThreeByte38 => (true, Immediate::None),
+            Map::ThreeByte3A => (true, Immediate::Byte),
+        };
+
+        if has_modrm {
+            let reg = self.modrm()?;
+            // test r/m, imm is the only form of F6 and F7 with an immediate
+            if map == Map::OneByte && (opcode == 0xF6 || opcode == 0xF7) && reg < 2 {
+                immediate = match opcode {
+                    0xF6 => Immediate::Byte,
+                    _ => Immediate::Full,
+                };
+            }
+        }
+
+        let full = if operand_size_prefix { 2 } else { 4 };
+        let size = match immediate {
+            Immediate::None => 0,
+            Immediate::Byte => 1,
+            Immediate::Word => 2,
+            Immediate::Dword => 4,
+            Immediate::Enter => 3,
+            Immediate::Full => full,
+            Immediate::Quad if rex_w => 8,
+            Immediate::Quad => full,
+            Immediate::Offset if address_size_prefix => 4,
+            Immediate::Offset => 8,
+        };
+        let branch = match map {
+            Map::OneByte => matches!(opcode, 0x70..=0x7F | 0xE0..=0xE3 | 0xE8 | 0xE9 | 0xEB),
+            Map::TwoByte => matches!(opcode, 0x80..=0x8F),
+            _ => false,
+        };
+        if branch {
+            self.relative = Some((self.position, size));
+        }
+        self.skip(size)?;
+
+        self.finish()
+    }
+
+    fn vector(&mut self, prefix: u8) -> Result<usize> {
+        let map = match prefix {
+            0xC5 => {
+                self.skip(1)?;
+                1
+            }
+            0xC4 => {
+                let map = self.next()? & 0x1F;
+                self.skip(1)?;
+                map
+            }
+            _ => {
+                let map = self.next()? & 0x07;
+                self.skip(2)?;
+                map
+            }
+        };
+
+        let opcode = self.next()?;
+        // vzeroupper and vzeroall take no operands
+        if prefix != 0x62 && map == 1 && opcode == 0x77 {
+            return self.finish();
+        }
+
+        self.modrm()?;
+        let immediate = match (map, opcode) {
+            (1, 0x70..=0x73 | 0xC2 | 0xC4..=0xC6) => 1,
+            (1 | 2 | 5 | 6, _) => 0,
+            (3, _) => 1,
+            _ => bail!("Unsupported vector opcode map {map} in {:02X?}", self.code),
+        };
+        self.skip(immediate)?;
+
+        self.finish()
+    }
+
+    // Skips the ModRM byte and its SIB and displacement, returning the reg field
+    fn modrm(&mut self) -> Result<u8> {
+        let modrm = self.next()?;
+        let mode = modrm >> 6;
+        let reg = (modrm >> 3) & 0x07;
+        let rm = modrm & 0x07;
+
+        if mode == 3 {
+            return Ok(reg);
+        }
+
+        let mut displacement = match mode {
+            1 => 1,
+            2 => 4,
+            _ => 0,
+        };
+        if rm == 4 {
+            let sib = self.next()?;
+            if mode == 0 && sib & 0x07 == 5 {
+                displacement = 4;
+            }
+        } else if mode == 0 && rm == 5 {
+            self.relative = Some((self.position, 4));
+            displacement = 4;
+        }
+        self.skip(displacement)?;
+
+        Ok(reg)
+    }
+
+    fn finish(&self) -> Result<usize> {
+        if self.position > MAX_INSTRUCTION_LENGTH {
+            bail!(
+                "Instruction {:02X?} is too long",
+                &self.code[..self.position]
+            );
+        }
+        Ok(self.position)
+    }
+}
+
+fn one_byte(opcode: u8) -> Result<(bool, Immediate)> {
+    Ok(match opcode {
+        0x00..=0x3F if opcode & 0x07 < 4 => (true, Immediate::None),
+        0x00..=0x3F if opcode & 0x07 == 4 => (false, Immediate::Byte),
+        0x00..=0x3F if opcode & 0x07 == 5 => (false, Immediate::Full),
+        0x50..=0x5F | 0x90..=0x99 | 0x9B..=0x9F => (false, Immediate::None),
+        0x63 => (true, Immediate::None),
+        0x68 => (false, Immediate::Full),
+        0x69 => (true, Immediate::Full),
+        0x6A => (false, Immediate::Byte),
+        0x6B => (true, Immediate::Byte),
+        0x6C..=0x6F => (false, Immediate::None),
+        0x70..=0x7F => (false, Immediate::Byte),
+        0x80 | 0x83 => (true, Immediate::Byte),
+        0x81 => (true, Immediate::Full),
+        0x84..=0x8F => (true, Immediate::None),
+        0xA0..=0xA3 => (false, Immediate::Offset),
+        0xA4..=0xA7 | 0xAA..=0xAF => (false, Immediate::None),
+        0xA8 => (false, Immediate::Byte),
+        0xA9 => (false, Immediate::Full),
+        0xB0..=0xB7 => (false, Immediate::Byte),
+        0xB8..=0xBF => (false, Immediate::Quad),
+        0xC0 | 0xC1 | 0xC6 => (true, Immediate::Byte),
+        0xC2 | 0xCA => (false, Immediate::Word),
+        0xC3 | 0xC9 | 0xCB | 0xCC | 0xCF => (false, Immediate::None),
+        0xC7 => (true, Immediate::Full),
+        0xC8 => (false, Immediate::Enter),
+        0xCD => (false, Immediate::Byte),
+        0xD0..=0xD3 | 0xD8..=0xDF => (true, Immediate::None),
+        0xD7 => (false, Immediate::None),
+        0xE0..=0xE7 | 0xEB => (false, Immediate::Byte),
+        0xE8 | 0xE9 => (false, Immediate::Dword),
+        0xEC..=0xEF | 0xF1 | 0xF4 | 0xF5 | 0xF8..=0xFD => (false, Immediate::None),
+        0xF6 | 0xF7 | 0xFE | 0xFF => (true, Immediate::None),
+        _ => bail!("Opcode {opcode:02X} isn't valid in 64-bit mode"),
+    })
+}
+
+fn two_byte(opcode: u8) -> Result<(bool, Immediate)> {
+    Ok(match opcode {
+        0x05..=0x09 | 0x0B | 0x0E | 0x30..=0x37 | 0x77 => (false, Immediate::None),
+        0x70..=0x73 | 0xA4 | 0xAC | 0xBA | 0xC2 | 0xC4..=0xC6 => (true, Immediate::Byte),
+        0x80..=0x8F => (false, Immediate::Dword),
+        0xA0..=0xA2 | 0xA8..=0xAA | 0xC8..=0xCF => (false, Immediate::None),
+        0x04 | 0x0A | 0x0C | 0x0F | 0x24..=0x27 | 0x39 | 0x3B..=0x3F | 0x7A | 0x7B => {
+            bail!("Opcode 0F {opcode:02X} isn't supported")
+        }
+        _ => (true, Immediate::None),
+    })
+}
+
+pub fn rel32(next_instruction: usize, target: usize) -> Result<[u8; 4]> {
+    let displacement = target as i64 - next_instruction as i64;
+    match i32::try_from(displacement) {
+        Ok(displacement) => Ok(displacement.to_le_bytes()),
+        Err(_) => bail!("{target:#X} is out of rel32 range of {next_instruction:#X}"),
+    }
+}
+
+#[cfg(test)]
+pub fn jump(from: usize, to: usize) -> Result<[u8; 5]> {
+    let displacement = rel32(from + 5, to)?;
+    Ok([
+        0xE9,
+        displacement[0],
+        displacement[1],
+        displacement[2],
+        displacement[3],
+    ])
+}
+
+// Moves the instructions in code from one address to another, fixing up relative operands
+#[cfg(test)]
+pub fn relocate(code: &[u8], from: usize, to: usize) -> Result<Vec<u8>> {
+    let mut relocated = Vec::new();
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let instruction = decode(&code[offset..])?;
+        let bytes = &code[offset..(offset + instruction.length)];
+        let next_instruction = from + offset + instruction.length;
+        offset += instruction.length;
+
+        let Some((position, size)) = instruction.relative else {
+            relocated.extend_from_slice(bytes);
+            continue;
+        };
+
+        let displacement = match size {
+            1 => bytes[position] as i8 as i64,
+            _ => i32::from_le_bytes(bytes[position..(position + 4)].try_into()?) as i64,
+        };
+        let target = (next_instruction as i64 + displacement) as usize;
+        if (from..(from + code.len())).contains(&target) && target != from {
+            bail!(
+                "Instruction at {:#X} branches into the relocated code",
+                next_instruction - instruction.length
+            );
+        }
+
+        let new_address = to + relocated.len();
+        match (size, bytes[position - 1]) {
+            (4, _) => {
+                let new_next = new_address + instruction.length;
+                relocated.extend_from_slice(&bytes[..position]);
+                relocated.extend_from_slice(&rel32(new_next, target)?);
+                relocated.extend_from_slice(&bytes[(position + 4)..]);
+            }
+            // jmp rel8 becomes jmp rel32
+            (1, 0xEB) => relocated.extend_from_slice(&jump(new_address, target)?),
+            // jcc rel8 becomes jcc rel32
+            (1, opcode @ 0x70..=0x7F) => {
+                relocated.extend_from_slice(&[0x0F, opcode + 0x10]);
+                relocated.extend_from_slice(&rel32(new_address + 6, target)?);
+            }
+            _ => bail!("Can't relocate instruction {bytes:02X?}"),
+        }
+    }
+
+    Ok(relocated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Code, length and relative operand
+    type Case = (&'static [u8], usize, Option<(usize, usize)>);
+
+    // Encodings and lengths as disassembled by objdump
+    const INSTRUCTIONS: &[Case] = &[
+        // nop, ret, push rbx
+        (&[0x90], 1, None),
+        (&[0xC3], 1, None),
+        (&[0x53], 1, None),
+        // mov rax, [rsp+8]; mov r12, [r13]; sub rsp, 0x28; sub rsp, 0x128
+        (&[0x48, 0x8B, 0x44, 0x24, 0x08], 5, None),
+        (&[0x4D, 0x8B, 0x65, 0x00], 4, None),
+        (&[0x48, 0x83, 0xEC, 0x28], 4, None),
+        (&[0x48, 0x81, 0xEC, 0x28, 0x01, 0x00, 0x00], 7, None),
+        // mov rax, imm64; mov ax, imm16; test cl, 1; test ecx, imm32
+        (&[0x48, 0xB8, 1, 2, 3, 4, 5, 6, 7, 8], 10, None),
+        (&[0x66, 0xB8, 0x34, 0x12], 4, None),
+        (&[0xF6, 0xC1, 0x01], 3, None),
+        (&[0xF7, 0xC1, 1, 2, 3, 4], 6, None),
+        // movss xmm4, [rip]; movss xmm5, [rsp+0x58]; mov dword [rip], imm32
+        (&[0xF3, 0x0F, 0x10, 0x25, 1, 2, 3, 4], 8, Some((4, 4))),
+        (&[0xF3, 0x0F, 0x10, 0x6C, 0x24, 0x58], 6, None),
+        (&[0xC7, 0x05, 1, 2, 3, 4, 5, 6, 7, 8], 10, Some((2, 4))),
+        // call, jmp, jmp short, je short, je near
+        (&[0xE8, 1, 2, 3, 4], 5, Some((1, 4))),
+        (&[0xE9, 1, 2, 3, 4], 5, Some((1, 4))),
+        (&[0xEB, 0x10], 2, Some((1, 1))),
+        (&[0x74, 0x10], 2, Some((1, 1))),
+        (&[0x0F, 0x84, 1, 2, 3, 4], 6, Some((2, 4))),
+        // nop word [rax+rax]; nop dword [rax]
+        (&[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00], 6, None),
+        (&[0x0F, 0x1F, 0x80, 0, 0, 0, 0], 7, None),
+        // pshufb xmm0, xmm1; palignr xmm0, xmm1, 8
+        (&[0x66, 0x0F, 0x38, 0x00, 0xC1], 5, None),
+        (&[0x66, 0x0F, 0x3A, 0x0F, 0xC1, 0x08], 6, None),
+        // vmovaps xmm0, xmm1; vbroadcastss xmm0, [rip]
+        (&[0xC5, 0xF8, 0x28, 0xC1], 4, None),
+        (&[0xC4, 0xE2, 0x79, 0x18, 0x05, 1, 2, 3, 4], 9, Some((5, 4))),
+    ];
+
+    #[test]
+    fn decodes_lengths_and_relative_operands() {
+        for (code, length, relative) in INSTRUCTIONS {
+            let mut padded = code.to_vec();
+            padded.extend_from_slice(&[0xCC; MAX_INSTRUCTION_LENGTH]);
+            let instruction = decode(&padded).unwrap();
+            assert_eq!(instruction.length, *length, "length of {code:02X?}");
+            assert_eq!(instruction.relative, *relative, "operand of {code:02X?}");
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_instructions() {
+        assert!(decode(&[0x48, 0xB8, 1, 2]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn covers_whole_instructions() {
+        let code = [0x48, 0x83, 0xEC, 0x28, 0x90, 0xE8, 1, 2, 3, 4, 0xC3];
+        assert_eq!(covering_length(&code, 5).unwrap(), 5);
+        assert_eq!(covering_length(&code, 6).unwrap(), 10);
+    }
+}